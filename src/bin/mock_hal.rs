@@ -0,0 +1,461 @@
+//! Stands in for the sub code's HAL, so the sim can be driven without it.
+//!
+//! Commands are read one per line from `--script <path>`, or from stdin:
+//!
+//! ```text
+//! motors <thrust for each thruster, by id from 0>
+//! motor <id> <thrust> [<id> <thrust>...]
+//! botcam on|off
+//! zed on|off
+//! botcam-encoding raw|qoi|png|jpeg
+//! zed-encoding raw|qoi|png|jpeg
+//! localization <9 rotation (column-major)> <3 position> <3 velocity>
+//! ack
+//! reset
+//! coin-flip
+//! teleport <9 rotation (column-major)> <3 position> <3 velocity> <3 angular velocity>
+//! pause
+//! resume
+//! step [ticks]
+//! seed <seed>
+//! wait <seconds>
+//! ```
+//!
+//! Everything the sim sends is printed, and camera frames are saved as PNGs to `--image-dir`.
+//! With `--auto-ack`, the first sensor packet of every tick is acknowledged to drive the sim in
+//! lockstep mode.
+//! With `--image-transport shm`, camera frames are read from shared memory like the sim is told
+//! to with the same flag, using the same `--shm-socket` and `--shm-prefix`.
+//! The mock HAL exits once the script ends.
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use futures_lite::{future::block_on, io::AssertAsync};
+use image::ExtendedColorType;
+use subsimgpt2::{
+    config::Config,
+    protocol::{
+        Hello, ImageEncoding, ImageMessage, IncomingMessage, Message, MessageKind, OutgoingMessage,
+        PROTOCOL_VERSION, SIM_CONNECTION_KINDS, Stamp, read_frame,
+        shm::{self, ShmImage, ShmRingReader},
+    },
+};
+
+const DEFAULT_INCOMING: &str = "127.0.0.1:1817";
+const DEFAULT_OUTGOING: &str = "127.0.0.1:1818";
+const DEFAULT_IMAGE_DIR: &str = "mock_hal_images";
+
+const RECONNECT_PERIOD: Duration = Duration::from_millis(1000);
+
+fn main() -> Result {
+    let config = Config::load()?;
+    let incoming: SocketAddr = config
+        .get("hal-incoming")?
+        .unwrap_or(DEFAULT_INCOMING.parse()?);
+    let outgoing: SocketAddr = config
+        .get("hal-outgoing")?
+        .unwrap_or(DEFAULT_OUTGOING.parse()?);
+    // The sim connects by default, so the mock listens unless told otherwise
+    let connect = config.get("connect")?.unwrap_or(false);
+    let image_dir: PathBuf = config
+        .get("image-dir")?
+        .unwrap_or_else(|| DEFAULT_IMAGE_DIR.into());
+    let script: Option<PathBuf> = config.get("script")?;
+    let auto_ack = config.get("auto-ack")?.unwrap_or(false);
+    let shm = match config.get::<String>("image-transport")?.as_deref() {
+        None | Some("tcp") => false,
+        Some("shm") => true,
+        Some(other) => return Err(format!("Unknown image transport {other:?}").into()),
+    };
+    #[cfg(not(unix))]
+    if shm {
+        return Err("Shared memory image transport needs Unix sockets".into());
+    }
+    #[cfg(unix)]
+    let shm_socket: PathBuf = config
+        .get("shm-socket")?
+        .unwrap_or_else(|| shm::DEFAULT_SOCKET.into());
+    let shm_prefix: PathBuf = config
+        .get("shm-prefix")?
+        .unwrap_or_else(|| shm::DEFAULT_PREFIX.into());
+    std::fs::create_dir_all(&image_dir)?;
+
+    // Bound up front so the sim can queue its connections while the commands one is set up
+    let listener = (!connect)
+        .then(|| TcpListener::bind(incoming))
+        .transpose()?;
+    #[cfg(unix)]
+    let shm_listener = (shm && !connect)
+        .then(|| {
+            // Left behind if the mock was killed
+            let _ = std::fs::remove_file(&shm_socket);
+            UnixListener::bind(&shm_socket)
+        })
+        .transpose()?;
+    let mut commands = if connect {
+        establish(outgoing)
+    } else {
+        TcpListener::bind(outgoing)?.accept()?.0
+    };
+    commands.set_nodelay(true)?;
+    println!("Connected to sim at {}", commands.peer_addr()?);
+    let hello = IncomingMessage::Hello(Hello {
+        version: PROTOCOL_VERSION,
+        ..default()
+    });
+    commands.write_all(&hello.to_frame()?)?;
+    let commands = Arc::new(Mutex::new(commands));
+
+    let sink = Arc::new(Sink {
+        image_dir,
+        acks: auto_ack.then(|| commands.clone()),
+        acked_ticks: AtomicU64::new(0),
+        shm_prefix,
+    });
+    match listener {
+        Some(listener) => {
+            let sink = sink.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let sink = sink.clone();
+                    thread::spawn(move || sink.receive(stream));
+                }
+            });
+        }
+        None => {
+            let shm_connections = if shm { shm::CAMERAS.len() } else { 0 };
+            for _ in shm_connections..SIM_CONNECTION_KINDS.len() {
+                let sink = sink.clone();
+                thread::spawn(move || sink.receive(establish(incoming)));
+            }
+        }
+    }
+    #[cfg(unix)]
+    if shm {
+        receive_shm(&sink, shm_listener, &shm_socket);
+    }
+
+    let lines: Box<dyn BufRead> = match script {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
+    };
+    for (number, line) in lines.lines().enumerate() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match parse_command(line) {
+            Ok(Command::Send(message)) => {
+                commands.lock().unwrap().write_all(&message.to_frame()?)?
+            }
+            Ok(Command::Wait(duration)) => thread::sleep(duration),
+            Err(e) => eprintln!("line {}: {e}", number + 1),
+        }
+    }
+    Ok(())
+}
+
+fn establish(address: SocketAddr) -> TcpStream {
+    loop {
+        match TcpStream::connect(address) {
+            Ok(stream) => return stream,
+            Err(_) => thread::sleep(RECONNECT_PERIOD),
+        }
+    }
+}
+
+/// Receives camera notifications over the shared memory socket, from connections to a listener
+/// bound up front or made once per camera
+#[cfg(unix)]
+fn receive_shm(sink: &Arc<Sink>, listener: Option<UnixListener>, socket: &Path) {
+    match listener {
+        Some(listener) => {
+            let sink = sink.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let sink = sink.clone();
+                    thread::spawn(move || sink.receive(stream));
+                }
+            });
+        }
+        None => {
+            for _ in shm::CAMERAS {
+                let sink = sink.clone();
+                let socket = socket.to_owned();
+                thread::spawn(move || sink.receive(establish_unix(&socket)));
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn establish_unix(path: &Path) -> UnixStream {
+    loop {
+        match UnixStream::connect(path) {
+            Ok(stream) => return stream,
+            Err(_) => thread::sleep(RECONNECT_PERIOD),
+        }
+    }
+}
+
+enum Command {
+    Send(IncomingMessage),
+    Wait(Duration),
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+    let floats = |count: usize| -> Result<Vec<f32>, String> {
+        if args.len() != count {
+            return Err(format!("{name} takes {count} numbers, got {}", args.len()));
+        }
+        args.iter()
+            .map(|arg| arg.parse().map_err(|e| format!("{arg:?}: {e}")))
+            .collect()
+    };
+    let single = || match args.as_slice() {
+        [arg] => Ok(*arg),
+        _ => Err(format!("{name} takes one argument")),
+    };
+    // One Motors message holds at most this many thrusts
+    let max_thrusts = u8::MAX as usize;
+    let message = match name {
+        "motors" if args.len() > max_thrusts => {
+            return Err(format!("motors takes at most {max_thrusts} thrusts"));
+        }
+        "motor" if args.len() > 2 * max_thrusts => {
+            return Err(format!("motor takes at most {max_thrusts} pairs"));
+        }
+        "motors" => IncomingMessage::Motors(
+            floats(args.len())?
+                .into_iter()
+                .enumerate()
+                .map(|(id, thrust)| (id as u8, thrust))
+                .collect(),
+        ),
+        "motor" => {
+            if args.is_empty() || !args.len().is_multiple_of(2) {
+                return Err("motor takes <id> <thrust> pairs".into());
+            }
+            let thrusts = args
+                .chunks_exact(2)
+                .map(|pair| {
+                    let id = pair[0].parse().map_err(|e| format!("{:?}: {e}", pair[0]))?;
+                    let thrust = pair[1].parse().map_err(|e| format!("{:?}: {e}", pair[1]))?;
+                    Ok((id, thrust))
+                })
+                .collect::<Result<_, String>>()?;
+            IncomingMessage::Motors(thrusts)
+        }
+        "botcam" => IncomingMessage::BotcamOn(parse_switch(single()?)?),
+        "zed" => IncomingMessage::ZedOn(parse_switch(single()?)?),
+        "botcam-encoding" => IncomingMessage::BotcamEncoding(parse_encoding(single()?)?),
+        "zed-encoding" => IncomingMessage::ZedEncoding(parse_encoding(single()?)?),
+        "ack" => IncomingMessage::SensorAck,
+        "reset" => IncomingMessage::ResetSub,
+        "coin-flip" => IncomingMessage::CoinFlip,
+        "pause" => IncomingMessage::Pause,
+        "resume" => IncomingMessage::Resume,
+        "step" => IncomingMessage::Step(match args.as_slice() {
+            [] => 1,
+            [ticks] => ticks.parse().map_err(|e| format!("{ticks:?}: {e}"))?,
+            _ => return Err("step takes at most one argument".into()),
+        }),
+        "seed" => IncomingMessage::Seed(single()?.parse().map_err(|e| format!("seed: {e}"))?),
+        "teleport" => {
+            let data = floats(18)?;
+            IncomingMessage::TeleportSub {
+                rotation: Mat3::from_cols_slice(&data[..9]),
+                position: Vec3::from_slice(&data[9..12]),
+                velocity: Vec3::from_slice(&data[12..15]),
+                angular_velocity: Vec3::from_slice(&data[15..18]),
+            }
+        }
+        "localization" => {
+            let data = floats(15)?;
+            IncomingMessage::LocalizationEstimate {
+                rotation: Mat3::from_cols_slice(&data[..9]),
+                position: Vec3::from_slice(&data[9..12]),
+                velocity: Vec3::from_slice(&data[12..15]),
+            }
+        }
+        "wait" => {
+            let seconds = floats(1)?[0];
+            return Ok(Command::Wait(Duration::from_secs_f32(seconds)));
+        }
+        _ => return Err(format!("unknown command {name:?}")),
+    };
+    Ok(Command::Send(message))
+}
+
+fn parse_switch(arg: &str) -> Result<bool, String> {
+    match arg {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected on or off, got {arg:?}")),
+    }
+}
+
+fn parse_encoding(arg: &str) -> Result<ImageEncoding, String> {
+    match arg {
+        "raw" => Ok(ImageEncoding::Raw),
+        "qoi" => Ok(ImageEncoding::Qoi),
+        "png" => Ok(ImageEncoding::Png),
+        "jpeg" => Ok(ImageEncoding::Jpeg),
+        _ => Err(format!("expected raw, qoi, png or jpeg, got {arg:?}")),
+    }
+}
+
+/// Where everything received from the sim goes
+struct Sink {
+    image_dir: PathBuf,
+    /// Acknowledges the first sensor packet of every tick over the commands connection, for
+    /// lockstep mode
+    acks: Option<Arc<Mutex<TcpStream>>>,
+    /// Ticks up to and including the last one acknowledged, since sensors sent as their own
+    /// message kinds arrive over separate connections
+    acked_ticks: AtomicU64,
+    /// Where the rings of camera frames sent through shared memory are
+    shm_prefix: PathBuf,
+}
+
+impl Sink {
+    /// Prints or saves everything received over one connection from the sim.
+    ///
+    /// Blocks the calling thread, which is dedicated to the connection.
+    fn receive(&self, stream: impl Read) {
+        let mut rings = HashMap::new();
+        let mut stream = AssertAsync::new(stream);
+        loop {
+            let frame = match block_on(read_frame(&mut stream)) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Failed to read from sim: {e}");
+                    return;
+                }
+            };
+            match OutgoingMessage::decode(frame.kind, &frame.payload) {
+                Ok(OutgoingMessage::BotcamImage(image)) => {
+                    save_image("botcam", &image, &self.image_dir)
+                }
+                Ok(OutgoingMessage::ZedImage(image)) => save_image("zed", &image, &self.image_dir),
+                Ok(OutgoingMessage::ShmImage(notification)) => {
+                    match self.read_shm_image(&mut rings, &notification) {
+                        Ok(image) => {
+                            let camera = match notification.camera {
+                                MessageKind::BotcamImage => "botcam",
+                                _ => "zed",
+                            };
+                            save_image(camera, &image, &self.image_dir)
+                        }
+                        Err(e) => eprintln!("Failed to read {:?} frame: {e}", notification.camera),
+                    }
+                }
+                Ok(OutgoingMessage::Sensors(sensors)) => {
+                    println!("{sensors:?}");
+                    self.ack(sensors.stamp);
+                }
+                Ok(
+                    message @ (OutgoingMessage::Imu(stamp, ..)
+                    | OutgoingMessage::Dvl(stamp, _)
+                    | OutgoingMessage::Depth(stamp, _)),
+                ) => {
+                    println!("{message:?}");
+                    self.ack(stamp);
+                }
+                Ok(message) => println!("{message:?}"),
+                Err(e) => eprintln!("Skipping frame from sim: {e}"),
+            }
+        }
+    }
+
+    /// Acknowledges a sensor packet, unless one from the same tick already was
+    fn ack(&self, stamp: Stamp) {
+        let Some(acks) = &self.acks else {
+            return;
+        };
+        if self
+            .acked_ticks
+            .fetch_max(stamp.tick + 1, Ordering::Relaxed)
+            > stamp.tick
+        {
+            return;
+        }
+        let ack = IncomingMessage::SensorAck
+            .to_frame()
+            .expect("SensorAck has nothing to overflow");
+        if let Err(e) = acks.lock().unwrap().write_all(&ack) {
+            eprintln!("Failed to acknowledge sensors: {e}");
+        }
+    }
+
+    /// Copies a frame out of its camera's ring, opening the ring the first time
+    fn read_shm_image(
+        &self,
+        rings: &mut HashMap<MessageKind, ShmRingReader>,
+        notification: &ShmImage,
+    ) -> Result<ImageMessage, String> {
+        let ring = match rings.entry(notification.camera) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let path =
+                    shm::ring_path(&self.shm_prefix, notification.camera).ok_or("not a camera")?;
+                let ring = ShmRingReader::open(&path)
+                    .map_err(|e| format!("failed to open {}: {e}", path.display()))?;
+                entry.insert(ring)
+            }
+        };
+        let data = ring
+            .read(notification)
+            .ok_or("overwritten before it was read")?;
+        Ok(ImageMessage {
+            stamp: notification.stamp,
+            width: notification.width,
+            height: notification.height,
+            encoding: notification.encoding,
+            data,
+        })
+    }
+}
+
+fn save_image(camera: &str, image: &ImageMessage, image_dir: &Path) {
+    let path = image_dir.join(format!("{camera}-{:08}.png", image.stamp.tick));
+    let saved = image.to_rgba8().and_then(|rgba| {
+        image::save_buffer(
+            &path,
+            &rgba,
+            image.width,
+            image.height,
+            ExtendedColorType::Rgba8,
+        )
+    });
+    match saved {
+        Ok(()) => println!(
+            "{camera} {}x{} {:?} frame saved to {}",
+            image.width,
+            image.height,
+            image.encoding,
+            path.display()
+        ),
+        Err(e) => eprintln!("Failed to save {camera} frame: {e}"),
+    }
+}
@@ -0,0 +1,100 @@
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use bevy::{platform::collections::HashMap, prelude::*};
+
+/// Prefix of the environment variables read into the [`Config`]
+const ENV_PREFIX: &str = "SUBSIM_";
+
+/// Startup settings, keyed by their `--kebab-case` flag name.
+///
+/// Values come from (in increasing precedence) a `key = value` config file given by
+/// `--config` or `SUBSIM_CONFIG`, `SUBSIM_*` environment variables, then `--key value` flags.
+#[derive(Debug, Default, Clone, Resource)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let flags = parse_args(std::env::args().skip(1))?;
+        let env: HashMap<_, _> = std::env::vars()
+            .filter_map(|(key, value)| {
+                let key = key.strip_prefix(ENV_PREFIX)?;
+                Some((key.to_lowercase().replace('_', "-"), value))
+            })
+            .collect();
+
+        let mut config = Self::default();
+        if let Some(path) = flags.get("config").or(env.get("config")) {
+            config.values = parse_file(Path::new(path))?;
+        }
+        config.values.extend(env);
+        config.values.extend(flags);
+        Ok(config)
+    }
+
+    /// Parses the value for `key`, if it was set
+    pub fn get<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let Some(value) = self.values.get(key) else {
+            return Ok(None);
+        };
+        match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err(format!("Invalid value {value:?} for {key}: {e}").into()),
+        }
+    }
+}
+
+/// Settings given directly rather than loaded, e.g. in tests
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Config {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(values: I) -> Self {
+        Self {
+            values: values
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        }
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<HashMap<String, String>> {
+    let mut values = HashMap::default();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(format!("Unexpected argument {arg:?}").into());
+        };
+        if let Some((key, value)) = flag.split_once('=') {
+            values.insert(key.to_owned(), value.to_owned());
+            continue;
+        }
+        // A flag without a value is a switch
+        let value = match args.peek() {
+            Some(next) if !next.starts_with("--") => args.next().unwrap(),
+            _ => "true".to_owned(),
+        };
+        values.insert(flag.to_owned(), value);
+    }
+    Ok(values)
+}
+
+fn parse_file(path: &Path) -> Result<HashMap<String, String>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config {}: {e}", path.display()))?;
+    let mut values = HashMap::default();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("{}:{}: expected key = value", path.display(), number + 1))?;
+        values.insert(key.trim().to_owned(), value.trim().to_owned());
+    }
+    Ok(values)
+}
@@ -0,0 +1,135 @@
+//! Coordinate frame conventions for everything exchanged with the HAL.
+//!
+//! The sim works in Bevy's frame, with +Y up, and the sub's body faces +X with +Z on its right.
+//! The HAL instead sees world vectors (positions, velocities) in a [`WorldFrame`] and
+//! sensor readings in a [`BodyFrame`], with the sub's orientation being the rotation from its
+//! body frame to the world frame. The world's north is Bevy's +X.
+
+use std::str::FromStr;
+
+use bevy::prelude::*;
+
+use crate::config::Config;
+
+/// Axes of the world frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Reflect)]
+pub enum WorldFrame {
+    /// North, east, down
+    #[default]
+    Ned,
+    /// East, north, up
+    Enu,
+}
+
+impl WorldFrame {
+    /// Columns are the world frame's axes in Bevy's frame
+    pub const fn to_bevy(self) -> Mat3 {
+        match self {
+            WorldFrame::Ned => Mat3::from_cols_array(&[1., 0., 0., 0., 0., 1., 0., -1., 0.]),
+            WorldFrame::Enu => Mat3::from_cols_array(&[0., 0., 1., 1., 0., 0., 0., 1., 0.]),
+        }
+    }
+}
+
+impl FromStr for WorldFrame {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ned" => Ok(Self::Ned),
+            "enu" => Ok(Self::Enu),
+            _ => Err("expected ned or enu"),
+        }
+    }
+}
+
+/// Axes of the sub's body frame, which sensors mounted square with the body also measure in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Reflect)]
+pub enum BodyFrame {
+    /// Forward, right, down
+    #[default]
+    Frd,
+    /// Forward, left, up
+    Flu,
+}
+
+impl BodyFrame {
+    /// Columns are the body frame's axes in the sub's local Bevy frame
+    pub const fn to_bevy(self) -> Mat3 {
+        match self {
+            BodyFrame::Frd => Mat3::from_cols_array(&[1., 0., 0., 0., 0., 1., 0., -1., 0.]),
+            BodyFrame::Flu => Mat3::from_cols_array(&[1., 0., 0., 0., 0., -1., 0., 1., 0.]),
+        }
+    }
+}
+
+impl FromStr for BodyFrame {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "frd" => Ok(Self::Frd),
+            "flu" => Ok(Self::Flu),
+            _ => Err("expected frd or flu"),
+        }
+    }
+}
+
+/// Converts between Bevy's frame and the frames the HAL works in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Resource, Reflect)]
+#[reflect(Resource, Debug)]
+pub struct Frames {
+    pub world: WorldFrame,
+    pub body: BodyFrame,
+}
+
+impl Frames {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Self {
+            world: config.get("world-frame")?.unwrap_or_default(),
+            body: config.get("body-frame")?.unwrap_or_default(),
+        })
+    }
+
+    /// A world vector, such as a position or velocity, from the HAL's frame to Bevy's
+    pub fn world_to_bevy(&self, vector: Vec3) -> Vec3 {
+        self.world.to_bevy() * vector
+    }
+
+    pub fn world_from_bevy(&self, vector: Vec3) -> Vec3 {
+        self.world.to_bevy().transpose() * vector
+    }
+
+    /// A vector measured by a sensor, from the HAL's body frame to the sensor's local Bevy frame
+    pub fn body_to_bevy(&self, vector: Vec3) -> Vec3 {
+        self.body.to_bevy() * vector
+    }
+
+    pub fn body_from_bevy(&self, vector: Vec3) -> Vec3 {
+        self.body.to_bevy().transpose() * vector
+    }
+
+    /// The rotation from the body frame to the world frame, given the sub's rotation in Bevy
+    pub fn orientation_from_bevy(&self, rotation: Quat) -> Quat {
+        Quat::from_mat3(
+            &(self.world.to_bevy().transpose() * Mat3::from_quat(rotation) * self.body.to_bevy()),
+        )
+    }
+
+    pub fn orientation_to_bevy(&self, orientation: Quat) -> Quat {
+        Quat::from_mat3(
+            &(self.world.to_bevy()
+                * Mat3::from_quat(orientation)
+                * self.body.to_bevy().transpose()),
+        )
+    }
+
+    /// Roll, pitch and yaw of the sub, given its rotation in Bevy.
+    ///
+    /// The body frame is rotated from the world frame by yaw about z, then pitch about the
+    /// new y, then roll about the new x.
+    pub fn euler_from_bevy(&self, rotation: Quat) -> [f32; 3] {
+        let (yaw, pitch, roll) = self.orientation_from_bevy(rotation).to_euler(EulerRot::ZYX);
+        [roll, pitch, yaw]
+    }
+}
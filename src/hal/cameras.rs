@@ -1,21 +1,25 @@
-use async_io::Timer as AsyncTimer;
 use bevy::render::camera::ExtractedCamera;
 use bevy::render::extract_component::{ExtractComponent, ExtractComponentPlugin};
+use bevy::render::extract_resource::{ExtractResource, ExtractResourcePlugin};
 use bevy::render::{Render, RenderApp, RenderSet};
 use bevy::time::Timer;
 use std::sync::mpsc::channel;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
 use bevy::render::render_asset::RenderAssets;
 use bevy::render::render_resource::{Maintain, MapMode};
-use bevy::tasks::IoTaskPool;
 use bevy::{prelude::*, render::renderer::RenderDevice};
-use futures_lite::FutureExt as _;
+use subsimgpt2::protocol::{ImageEncoding, MessageKind, Stamp};
 
 use super::BotCamImage;
-use super::net::{OutgoingMessage, send};
+use super::clock::SimClock;
+use super::net::Outgoing;
 use super::{ImageExportSource, ZedImage, image_export::GpuImageExportSource};
 
+/// Resolution the ZED renders at, both eyes side by side
+pub const ZED_SIZE: UVec2 = UVec2::new(1280, 480);
+pub const BOTCAM_SIZE: UVec2 = UVec2::new(960, 540);
+
 #[derive(Debug, Default, Clone)]
 pub struct CameraPlugin;
 
@@ -23,12 +27,21 @@ impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((
             ExtractComponentPlugin::<CameraTimer>::default(),
+            ExtractComponentPlugin::<CameraEncoding>::default(),
             ExtractComponentPlugin::<ZedCamera>::default(),
             ExtractComponentPlugin::<BottomCamera>::default(),
+            ExtractResourcePlugin::<FrameStamp>::default(),
         ))
+        .init_resource::<FrameStamp>()
         .add_systems(PreUpdate, update_cam_timers)
-        .add_systems(PostUpdate, update_cam_enabled)
-        .register_type::<(CameraTimer, CameraEnabled, ZedCamera, BottomCamera)>();
+        .add_systems(PostUpdate, (update_frame_stamp, update_cam_enabled).chain())
+        .register_type::<(
+            CameraTimer,
+            CameraEnabled,
+            CameraEncoding,
+            ZedCamera,
+            BottomCamera,
+        )>();
 
         let render_app = app.sub_app_mut(RenderApp);
 
@@ -53,22 +66,35 @@ impl CameraTimer {
             timer: Timer::new(Duration::from_secs_f32(1.0 / hz), TimerMode::Repeating),
         }
     }
+
+    pub fn rate(&self) -> f32 {
+        1.0 / self.timer.duration().as_secs_f32()
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, Component, Reflect, ExtractComponent)]
 #[reflect(Debug, Clone, Component)]
 pub struct CameraEnabled(pub bool);
 
+/// Compression applied to this camera's frames, chosen by the HAL
+#[derive(Debug, Default, Clone, Copy, Component, Reflect, ExtractComponent)]
+#[reflect(Debug, Clone, Component)]
+pub struct CameraEncoding(pub ImageEncoding);
+
 #[derive(Debug, Default, Clone, Copy, Component, Reflect, ExtractComponent)]
 #[reflect(Component, Debug)]
-#[require(CameraTimer::from_rate(ZED_FRAME_RATE), CameraEnabled)]
+#[require(CameraTimer::from_rate(ZED_FRAME_RATE), CameraEnabled, CameraEncoding)]
 pub struct ZedCamera;
 
 pub const ZED_FRAME_RATE: f32 = 20.0;
 
 #[derive(Debug, Default, Clone, Component, Reflect, ExtractComponent)]
 #[reflect(Component, Debug)]
-#[require(CameraTimer::from_rate(BOT_CAM_FRAME_RATE), CameraEnabled)]
+#[require(
+    CameraTimer::from_rate(BOT_CAM_FRAME_RATE),
+    CameraEnabled,
+    CameraEncoding
+)]
 pub struct BottomCamera;
 
 pub const BOT_CAM_FRAME_RATE: f32 = 20.0;
@@ -86,23 +112,34 @@ pub fn update_cam_enabled(cameras: Query<(&CameraEnabled, &CameraTimer, &mut Cam
     }
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct Image {
-    pub width: u32,
-    pub height: u32,
-    pub buffer: Vec<u8>,
+/// Stamp of the frame being rendered, shared by its camera frames and ML targets
+#[derive(Debug, Default, Clone, Copy, Resource, ExtractResource)]
+pub struct FrameStamp(pub Stamp);
+
+fn update_frame_stamp(
+    mut stamp: ResMut<FrameStamp>,
+    clock: Res<SimClock>,
+    time: Res<Time<Virtual>>,
+) {
+    stamp.0 = clock.stamp(time.elapsed());
 }
 
-fn get_image(
+/// Reads a camera frame back from the GPU and queues it, without copying it when it can go
+/// straight into shared memory
+fn send_image(
+    kind: MessageKind,
     image: &Handle<ImageExportSource>,
+    stamp: Stamp,
+    encoding: ImageEncoding,
     sources: &RenderAssets<GpuImageExportSource>,
     render_device: &RenderDevice,
-) -> Result<Image> {
+    outgoing: &Outgoing,
+) -> Result {
     let gpu_source = sources.get(image).ok_or("Image does not exist")?;
     let width = gpu_source.source_size.width;
     let height = gpu_source.source_size.height;
     let image_len = (width * height * 4) as usize;
-    let image_bytes = {
+    {
         let slice = gpu_source.buffer.slice(..);
 
         {
@@ -115,69 +152,62 @@ fn get_image(
             rx.recv()??;
         }
 
-        assert_eq!(image_len, slice.get_mapped_range().len());
-        slice.get_mapped_range().to_vec()
-    };
+        let pixels = slice.get_mapped_range();
+        assert_eq!(image_len, pixels.len());
+        outgoing.send_image(kind, stamp, width, height, &pixels, encoding);
+    }
 
     gpu_source.buffer.unmap();
-    Ok(Image {
-        width,
-        height,
-        buffer: image_bytes,
-    })
+    Ok(())
 }
 
 // TODO: better rate limiting
 pub fn send_zed_image(
-    zed_cam: Query<(), (With<ExtractedCamera>, With<ZedCamera>)>,
+    zed_cam: Query<&CameraEncoding, (With<ExtractedCamera>, With<ZedCamera>)>,
     zed_image: Option<Res<ZedImage>>,
     sources: Res<RenderAssets<GpuImageExportSource>>,
     render_device: Res<RenderDevice>,
+    outgoing: Res<Outgoing>,
+    stamp: Res<FrameStamp>,
 ) -> Result {
-    if zed_cam.is_empty() {
+    let Some(&CameraEncoding(encoding)) = zed_cam.iter().next() else {
         return Ok(());
-    }
+    };
     let Some(zed_image) = zed_image else {
         return Ok(());
     };
-    let image = get_image(&zed_image.0, &*sources, &*render_device)?;
-    IoTaskPool::get()
-        .spawn(
-            async move { send(OutgoingMessage::ZedImage(SystemTime::now(), image)).await }.or(
-                async {
-                    AsyncTimer::after(Duration::from_secs_f32(1.0)).await;
-                    Err("Cancelled".into())
-                },
-            ),
-        )
-        .detach();
-
-    Ok(())
+    send_image(
+        MessageKind::ZedImage,
+        &zed_image.0,
+        stamp.0,
+        encoding,
+        &sources,
+        &render_device,
+        &outgoing,
+    )
 }
 
 pub fn send_botcam_image(
-    bot_cam: Query<(), (With<ExtractedCamera>, With<BottomCamera>)>,
+    bot_cam: Query<&CameraEncoding, (With<ExtractedCamera>, With<BottomCamera>)>,
     botcam_image: Option<Res<BotCamImage>>,
     sources: Res<RenderAssets<GpuImageExportSource>>,
     render_device: Res<RenderDevice>,
+    outgoing: Res<Outgoing>,
+    stamp: Res<FrameStamp>,
 ) -> Result {
-    if bot_cam.is_empty() {
+    let Some(&CameraEncoding(encoding)) = bot_cam.iter().next() else {
         return Ok(());
-    }
+    };
     let Some(botcam_image) = botcam_image else {
         return Ok(());
     };
-    let image = get_image(&botcam_image.0, &*sources, &*render_device)?;
-    IoTaskPool::get()
-        .spawn(
-            async move { send(OutgoingMessage::BotcamImage(SystemTime::now(), image)).await }.or(
-                async {
-                    AsyncTimer::after(Duration::from_secs_f32(1.0)).await;
-                    Err("Cancelled".into())
-                },
-            ),
-        )
-        .detach();
-
-    Ok(())
+    send_image(
+        MessageKind::BotcamImage,
+        &botcam_image.0,
+        stamp.0,
+        encoding,
+        &sources,
+        &render_device,
+        &outgoing,
+    )
 }
@@ -0,0 +1,189 @@
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+use subsimgpt2::{
+    config::Config,
+    protocol::{IncomingMessage, Stamp},
+};
+
+use super::sensors::SensorTimer;
+
+/// Decides when fixed ticks run: freely in real time, paused, or in lockstep with the HAL.
+///
+/// Whenever the sim is not running freely, virtual time is paused and only advanced by whole
+/// fixed timesteps as ticks are released. In lockstep mode a tick is released whenever the HAL
+/// acknowledges the last sensor packet or sends new motor commands, so the sim runs as fast or
+/// as slow as the controller does. While paused, ticks are only released by [`IncomingMessage::Step`],
+/// over as many frames as it takes to run them without stalling the sim.
+/// When replaying a recording, one tick is released per frame so messages land on the same ticks
+/// every replay.
+#[derive(Debug, Clone, Resource, Reflect)]
+#[reflect(Debug, Clone, Resource)]
+pub struct SimClock {
+    pub lockstep: bool,
+    pub paused: bool,
+    pub replay: bool,
+    /// Clock that outgoing messages are stamped with
+    pub timestamps: TimestampClock,
+    /// Fixed ticks run so far
+    tick: u64,
+    /// Ticks to run while paused
+    pending_steps: u32,
+    /// A tick has been released in lockstep mode and the HAL has not responded to it yet
+    awaiting_hal: bool,
+}
+
+impl SimClock {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Self {
+            lockstep: config.get("lockstep")?.unwrap_or(false),
+            paused: config.get("paused")?.unwrap_or(false),
+            replay: config.get::<String>("replay")?.is_some(),
+            timestamps: config.get("timestamps")?.unwrap_or_default(),
+            tick: 0,
+            pending_steps: 0,
+            awaiting_hal: false,
+        })
+    }
+
+    /// Fixed ticks run so far
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Stamps a measurement taken now, `sim_time` into the sim
+    pub fn stamp(&self, sim_time: Duration) -> Stamp {
+        let time = match self.timestamps {
+            TimestampClock::Sim => sim_time,
+            TimestampClock::Wall => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time should not be before UNIX_EPOCH"),
+        };
+        Stamp {
+            time: time.as_secs_f64(),
+            tick: self.tick,
+        }
+    }
+}
+
+/// Which clock outgoing messages are stamped with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Reflect)]
+pub enum TimestampClock {
+    /// Seconds since the sim started, which stops while paused or waiting in lockstep
+    #[default]
+    Sim,
+    /// Seconds since the UNIX epoch
+    Wall,
+}
+
+impl FromStr for TimestampClock {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sim" => Ok(Self::Sim),
+            "wall" => Ok(Self::Wall),
+            _ => Err("expected sim or wall"),
+        }
+    }
+}
+
+/// Lets the next tick run in lockstep mode when no sensor sampled on this one, since the HAL
+/// has nothing to acknowledge
+pub fn skip_unsampled_ticks(timers: Query<&SensorTimer>, mut clock: ResMut<SimClock>) {
+    if !timers.iter().any(SensorTimer::sampled) {
+        clock.awaiting_hal = false;
+    }
+}
+
+pub fn count_tick(mut clock: ResMut<SimClock>) {
+    clock.tick += 1;
+}
+
+pub fn release_ticks(
+    mut clock: ResMut<SimClock>,
+    mut incoming: EventReader<IncomingMessage>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    mut time: ResMut<Time>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    for message in incoming.read() {
+        match message {
+            IncomingMessage::SensorAck | IncomingMessage::Motors(..) => clock.awaiting_hal = false,
+            IncomingMessage::Pause => clock.paused = true,
+            IncomingMessage::Resume => clock.paused = false,
+            IncomingMessage::Step(ticks) => {
+                clock.pending_steps = clock.pending_steps.saturating_add(*ticks)
+            }
+            _ => {}
+        }
+    }
+    if !clock.lockstep && !clock.paused && !clock.replay {
+        clock.pending_steps = 0;
+        if virtual_time.is_paused() {
+            virtual_time.unpause();
+        }
+        return;
+    }
+    // Virtual time only moves when ticks are released
+    virtual_time.pause();
+    let ticks = if clock.paused {
+        // Long steps are spread over frames, no more per frame than real time would run
+        let per_frame = virtual_time
+            .max_delta()
+            .div_duration_f64(fixed_time.timestep())
+            .max(1.0) as u32;
+        let ticks = clock.pending_steps.min(per_frame);
+        clock.pending_steps -= ticks;
+        ticks
+    } else if clock.replay {
+        1
+    } else if !clock.awaiting_hal {
+        clock.awaiting_hal = true;
+        1
+    } else {
+        0
+    };
+    if ticks == 0 {
+        return;
+    }
+    // Every released tick runs this frame
+    virtual_time.advance_by(fixed_time.timestep() * ticks);
+    *time = virtual_time.as_generic();
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    #[test]
+    fn long_steps_spread_over_frames() {
+        let mut world = World::new();
+        let clock = SimClock::from_config(&Config::default()).unwrap();
+        world.insert_resource(SimClock {
+            paused: true,
+            ..clock
+        });
+        world.init_resource::<Time>();
+        world.init_resource::<Time<Virtual>>();
+        world.init_resource::<Time<Fixed>>();
+        world.init_resource::<Events<IncomingMessage>>();
+        world.send_event(IncomingMessage::Step(u32::MAX));
+
+        world.run_system_once(release_ticks).unwrap();
+        let max_delta = world.resource::<Time<Virtual>>().max_delta();
+        let per_frame = max_delta.div_duration_f64(world.resource::<Time<Fixed>>().timestep());
+        assert_eq!(world.resource::<Time<Virtual>>().elapsed(), max_delta);
+        let pending = world.resource::<SimClock>().pending_steps;
+        assert_eq!(pending, u32::MAX - per_frame as u32);
+
+        // The rest are left for the following frames
+        world.run_system_once(release_ticks).unwrap();
+        assert_eq!(world.resource::<Time<Virtual>>().elapsed(), max_delta * 2);
+    }
+}
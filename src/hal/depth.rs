@@ -0,0 +1,175 @@
+use avian3d::prelude::Gravity;
+use bevy::prelude::*;
+use rand::Rng as _;
+use rand_distr::StandardNormal;
+
+use crate::sim::{SimRng, physics::WaterCollider};
+
+use super::sensors::{DEPTH_RATE, SensorTimer};
+
+/// Standard atmospheric pressure, in Pa
+pub const ATMOSPHERE: f32 = 101_325.0;
+/// In kg/m³
+pub const FRESH_WATER_DENSITY: f32 = 997.0;
+/// In kg/m³
+pub const SALT_WATER_DENSITY: f32 = 1029.0;
+
+/// A pressure sensor, read out as depth the way the HAL's driver would: assuming a standard
+/// atmosphere above water of [`DepthSensor::water_density`].
+///
+/// Defaults follow the Bar30.
+#[derive(Debug, Clone, Copy, Component, Reflect)]
+#[reflect(Component, Debug)]
+#[require(Transform, SensorTimer::from_rate(DEPTH_RATE))]
+pub struct DepthSensor {
+    /// In kg/m³, see [`FRESH_WATER_DENSITY`] and [`SALT_WATER_DENSITY`]
+    pub water_density: f32,
+    /// How far the air pressure at the surface is from a standard atmosphere, in Pa
+    pub atmospheric_offset: f32,
+    /// Deviation of the pressure noise, in Pa
+    pub noise: f32,
+    /// Smallest step in pressure the sensor reports, in Pa
+    pub resolution: f32,
+    /// Highest absolute pressure the sensor reports, in Pa
+    pub max_pressure: f32,
+    /// Absolute pressure, in Pa
+    pub pressure: f32,
+    pub depth: f32,
+}
+
+impl Default for DepthSensor {
+    fn default() -> Self {
+        Self {
+            water_density: FRESH_WATER_DENSITY,
+            atmospheric_offset: 0.0,
+            noise: 20.0,
+            resolution: 20.0,
+            max_pressure: 30e5,
+            pressure: ATMOSPHERE,
+            depth: 0.0,
+        }
+    }
+}
+
+impl DepthSensor {
+    /// Takes a reading `submersion` meters under the water, under gravity `g`, with the noise
+    /// scaled from a standard normal sample
+    pub fn read(&mut self, submersion: f32, g: f32, noise: f32) {
+        let pressure = ATMOSPHERE
+            + self.atmospheric_offset
+            + self.water_density * g * submersion
+            + noise * self.noise;
+        let pressure = if self.resolution > 0.0 {
+            (pressure / self.resolution).round() * self.resolution
+        } else {
+            pressure
+        };
+        self.pressure = pressure.clamp(0.0, self.max_pressure);
+        self.depth = (self.pressure - ATMOSPHERE) / (self.water_density * g);
+    }
+}
+
+/// How far below the top of the water `point` is, or `None` if it is not in the water
+fn submersion(water: &Query<(&GlobalTransform, &WaterCollider)>, point: Vec3) -> Option<f32> {
+    water.iter().find_map(|(transform, water)| {
+        let local = transform.affine().inverse().transform_point(point);
+        let inside = water.closest_point(local) == local;
+        let top = transform.transform_point(Vec3::Y * water.half_size.y);
+        inside.then_some(top.y - point.y)
+    })
+}
+
+pub fn update_depth_sensors(
+    mut depths: Query<(&GlobalTransform, &mut DepthSensor)>,
+    water: Query<(&GlobalTransform, &WaterCollider)>,
+    gravity: Res<Gravity>,
+    mut rng: ResMut<SimRng>,
+) {
+    let g = gravity.0.length();
+    for (transform, mut sensor) in depths.iter_mut() {
+        // Out of the water, the sensor only feels the air
+        let submersion = submersion(&water, transform.translation()).unwrap_or(0.0);
+        sensor.read(submersion, g, rng.sample(StandardNormal));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const G: f32 = 9.81;
+
+    fn exact() -> DepthSensor {
+        DepthSensor {
+            noise: 0.0,
+            resolution: 0.0,
+            ..default()
+        }
+    }
+
+    #[test]
+    fn surface() {
+        let mut sensor = exact();
+        sensor.read(0.0, G, 0.0);
+        assert_eq!(sensor.pressure, ATMOSPHERE);
+        assert_eq!(sensor.depth, 0.0);
+    }
+
+    #[test]
+    fn depth_round_trips() {
+        let mut sensor = exact();
+        for depth in [0.5, 3.0, 12.0] {
+            sensor.read(depth, G, 0.0);
+            assert!((sensor.depth - depth).abs() < 1e-4, "{}", sensor.depth);
+        }
+        // Read as fresh water, salt water seems deeper
+        sensor.water_density = SALT_WATER_DENSITY;
+        sensor.read(2.0, G, 0.0);
+        let read_as_fresh = (sensor.pressure - ATMOSPHERE) / (FRESH_WATER_DENSITY * G);
+        assert!(read_as_fresh > 2.0);
+        // The driver assumes a standard atmosphere, so any offset shows up as depth
+        sensor = DepthSensor {
+            atmospheric_offset: 1000.0,
+            ..exact()
+        };
+        sensor.read(0.0, G, 0.0);
+        assert!((sensor.depth - 1000.0 / (FRESH_WATER_DENSITY * G)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn quantized() {
+        let mut sensor = DepthSensor {
+            noise: 0.0,
+            ..default()
+        };
+        let resolution = sensor.resolution;
+        for depth in [0.0, 0.123, 1.0, 4.567] {
+            sensor.read(depth, G, 0.0);
+            let steps = sensor.pressure / sensor.resolution;
+            assert_eq!(steps, steps.round());
+            let step = sensor.resolution / (sensor.water_density * G);
+            assert!((sensor.depth - depth).abs() <= step / 2.0 + 1e-4);
+        }
+        // Noise of one step moves the reading by exactly one step
+        sensor.read(1.0, G, 0.0);
+        let quiet = sensor.pressure;
+        sensor.noise = resolution;
+        sensor.read(1.0, G, 1.0);
+        assert_eq!(sensor.pressure - quiet, resolution);
+    }
+
+    #[test]
+    fn clamped() {
+        let mut sensor = exact();
+        sensor.read(1000.0, G, 0.0);
+        assert_eq!(sensor.pressure, sensor.max_pressure);
+        assert_eq!(
+            sensor.depth,
+            (sensor.max_pressure - ATMOSPHERE) / (FRESH_WATER_DENSITY * G)
+        );
+        // No reading goes below vacuum, however noisy
+        sensor.noise = 1.0;
+        sensor.read(0.0, G, -1e6);
+        assert_eq!(sensor.pressure, 0.0);
+    }
+}
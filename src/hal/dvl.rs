@@ -0,0 +1,191 @@
+use std::{array, f32::consts::FRAC_PI_4};
+
+use avian3d::prelude::{
+    AngularVelocity, ComputedCenterOfMass, LinearVelocity, RigidBody, RigidBodyColliders,
+    SpatialQuery, SpatialQueryFilter,
+};
+use bevy::prelude::*;
+use rand::Rng as _;
+use rand_distr::StandardNormal;
+
+use crate::sim::{SimRng, physics::WaterCollider};
+
+use super::sensors::{DVL_RATE, SensorTimer};
+
+/// A four-beam Janus DVL, which bottom tracks off whatever colliders its beams reach.
+///
+/// Beams point down and out towards the front right, back right, back left and front left, in
+/// that order.
+#[derive(Debug, Clone, Copy, Component, Reflect)]
+#[reflect(Component, Debug)]
+#[require(Transform, SensorTimer::from_rate(DVL_RATE))]
+pub struct Dvl {
+    /// Radians each beam is tilted out from straight down
+    pub beam_angle: f32,
+    /// Beams lose the bottom closer than this
+    pub min_range: f32,
+    /// Beams lose the bottom further than this
+    pub max_range: f32,
+    /// Deviation of each beam's velocity, in m/s
+    pub noise: f32,
+    /// Deviation added to each beam's velocity per meter of range
+    pub noise_per_meter: f32,
+    /// In the DVL's local frame. Zero without [`Dvl::bottom_lock`].
+    pub velocity: Vec3,
+    /// Range along each beam to the bottom, if it sees it
+    pub ranges: [Option<f32>; 4],
+    /// Distance straight down to the bottom, averaged over the beams that see it
+    pub altitude: f32,
+}
+
+impl Default for Dvl {
+    fn default() -> Self {
+        Self {
+            beam_angle: 30f32.to_radians(),
+            min_range: 0.05,
+            max_range: 50.0,
+            noise: 0.002,
+            noise_per_meter: 0.001,
+            velocity: Vec3::ZERO,
+            ranges: [None; 4],
+            altitude: 0.0,
+        }
+    }
+}
+
+impl Dvl {
+    /// Whether enough beams see the bottom to solve for the velocity
+    pub fn bottom_lock(&self) -> bool {
+        self.ranges.iter().flatten().count() >= 3
+    }
+
+    /// Unit vectors along each beam, in the DVL's local frame
+    pub fn beams(&self) -> [Vec3; 4] {
+        let (sin, cos) = self.beam_angle.sin_cos();
+        [1.0, 3.0, 5.0, 7.0].map(|octant: f32| {
+            let (right, forward) = (octant * FRAC_PI_4).sin_cos();
+            Vec3::new(forward * sin, -cos, right * sin)
+        })
+    }
+
+    /// Solves by least squares for the velocity that best explains the speed measured along
+    /// each beam that sees the bottom. Needs at least three.
+    pub fn solve_velocity(&self, speeds: [Option<f32>; 4]) -> Option<Vec3> {
+        let mut normal = Mat3::ZERO;
+        let mut projected = Vec3::ZERO;
+        let mut valid = 0;
+        for (beam, speed) in self.beams().into_iter().zip(speeds) {
+            let Some(speed) = speed else {
+                continue;
+            };
+            normal += Mat3::from_cols(beam * beam.x, beam * beam.y, beam * beam.z);
+            projected += beam * speed;
+            valid += 1;
+        }
+        (valid >= 3).then(|| normal.inverse() * projected)
+    }
+}
+
+pub fn update_dvls(
+    mut dvls: Query<(&ChildOf, &GlobalTransform, &mut Dvl)>,
+    subs: Query<
+        (
+            &LinearVelocity,
+            &AngularVelocity,
+            &ComputedCenterOfMass,
+            &GlobalTransform,
+            &RigidBodyColliders,
+        ),
+        With<RigidBody>,
+    >,
+    water: Query<(&GlobalTransform, &WaterCollider)>,
+    spatial_query: SpatialQuery,
+    mut rng: ResMut<SimRng>,
+) -> Result {
+    let (water_transform, water_cuboid) = water.single()?;
+    let water_inverse = water_transform.affine().inverse();
+    for (parent, transform, mut dvl) in dvls.iter_mut() {
+        let (lin_vel, ang_vel, com, parent_transform, colliders) = subs.get(parent.0)?;
+        let origin = transform.translation();
+        let water_local = water_inverse.transform_point(origin);
+        let underwater = water_cuboid.closest_point(water_local) == water_local;
+
+        let filter = SpatialQueryFilter::from_excluded_entities(colliders.iter());
+        let beams = dvl.beams();
+        let ranges = beams.map(|beam| {
+            let direction = Dir3::new(transform.rotation() * beam).ok()?;
+            let hit = spatial_query.cast_ray(origin, direction, dvl.max_range, true, &filter)?;
+            (underwater && hit.distance >= dvl.min_range).then_some(hit.distance)
+        });
+
+        let hits: Vec<_> = beams
+            .iter()
+            .zip(ranges)
+            .filter_map(|(beam, range)| Some(-beam.y * range?))
+            .collect();
+        dvl.altitude = if hits.is_empty() {
+            0.0
+        } else {
+            hits.iter().sum::<f32>() / hits.len() as f32
+        };
+        dvl.ranges = ranges;
+
+        // Each beam that sees the bottom measures its speed along the beam
+        let offset = origin - *parent_transform * com.0;
+        let velocity = transform
+            .affine()
+            .inverse()
+            .transform_vector3(lin_vel.0 + ang_vel.cross(offset));
+        let speeds = array::from_fn(|i| {
+            let deviation = dvl.noise + dvl.noise_per_meter * ranges[i]?;
+            let noise: f32 = rng.sample(StandardNormal);
+            Some(beams[i].dot(velocity) + noise * deviation)
+        });
+        // A stale velocity would look valid to anything that does not check the beams
+        dvl.velocity = dvl.solve_velocity(speeds).unwrap_or(Vec3::ZERO);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VELOCITY: Vec3 = Vec3::new(0.4, -0.1, 0.25);
+
+    fn speeds(dvl: &Dvl) -> [Option<f32>; 4] {
+        dvl.beams().map(|beam| Some(beam.dot(VELOCITY)))
+    }
+
+    #[test]
+    fn four_beams() {
+        let dvl = Dvl::default();
+        let velocity = dvl.solve_velocity(speeds(&dvl)).unwrap();
+        assert!(velocity.abs_diff_eq(VELOCITY, 1e-5), "{velocity}");
+    }
+
+    #[test]
+    fn three_beams() {
+        let dvl = Dvl::default();
+        for lost in 0..4 {
+            let mut speeds = speeds(&dvl);
+            speeds[lost] = None;
+            let velocity = dvl.solve_velocity(speeds).unwrap();
+            assert!(velocity.abs_diff_eq(VELOCITY, 1e-5), "{velocity}");
+        }
+    }
+
+    #[test]
+    fn two_beams_lose_lock() {
+        let mut dvl = Dvl::default();
+        let mut speeds = speeds(&dvl);
+        speeds[0] = None;
+        speeds[2] = None;
+        assert_eq!(dvl.solve_velocity(speeds), None);
+
+        dvl.ranges = [Some(1.0), None, Some(1.0), Some(1.0)];
+        assert!(dvl.bottom_lock());
+        dvl.ranges[2] = None;
+        assert!(!dvl.bottom_lock());
+    }
+}
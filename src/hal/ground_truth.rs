@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use avian3d::prelude::{AngularVelocity, LinearVelocity, Position, Rotation};
+use bevy::prelude::*;
+use subsimgpt2::{
+    config::Config,
+    frames::Frames,
+    protocol::{GroundTruth, OutgoingMessage},
+};
+
+use crate::sim::sub::SubControls;
+
+use super::{clock::SimClock, net::Outgoing};
+
+/// Streams the sub's exact state to the HAL, so its estimator can be scored online
+#[derive(Debug, Clone, Resource, Reflect)]
+#[reflect(Resource, Debug)]
+pub struct GroundTruthStream {
+    /// `None` when ground truth is kept from the HAL
+    timer: Option<Timer>,
+}
+
+impl GroundTruthStream {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let rate: f32 = config.get("ground-truth-rate")?.unwrap_or(0.0);
+        if !rate.is_finite() || rate < 0.0 {
+            return Err(format!("Invalid ground truth rate {rate}").into());
+        }
+        Ok(Self {
+            timer: (rate > 0.0)
+                .then(|| Timer::new(Duration::from_secs_f32(1.0 / rate), TimerMode::Repeating)),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.timer.is_some()
+    }
+}
+
+pub fn send_ground_truth(
+    mut stream: ResMut<GroundTruthStream>,
+    subs: Query<(&Position, &Rotation, &LinearVelocity, &AngularVelocity), With<SubControls>>,
+    outgoing: Res<Outgoing>,
+    clock: Res<SimClock>,
+    frames: Res<Frames>,
+    time: Res<Time<Fixed>>,
+) {
+    let Some(timer) = &mut stream.timer else {
+        return;
+    };
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    for (position, rotation, linear_velocity, angular_velocity) in subs {
+        outgoing.send(OutgoingMessage::GroundTruth(GroundTruth {
+            stamp: clock.stamp(time.elapsed()),
+            rotation: Mat3::from_quat(frames.orientation_from_bevy(rotation.0)),
+            position: frames.world_from_bevy(position.0),
+            velocity: frames.world_from_bevy(linear_velocity.0),
+            angular_velocity: frames.world_from_bevy(angular_velocity.0),
+        }));
+    }
+}
@@ -0,0 +1,317 @@
+use std::fmt::Debug;
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use subsimgpt2::protocol::{
+    CameraInfo, Hello, IncomingMessage, MessageKind, PROTOCOL_VERSION, SensorInfo, SensorSet,
+};
+
+use crate::sim::sub::thruster::ThrusterOf;
+
+use super::{
+    BotCamImage, BottomCamera, CameraTimer, DepthSensor, Dvl, GroundTruthStream, ImageExportSource,
+    Imu, MLTargetOf, ZedCamera, ZedImage, net::Outgoing, sensors::SensorTimer,
+};
+
+/// Each camera, with the image its frames are exported from
+#[derive(SystemParam)]
+pub struct Cameras<'w, 's> {
+    zed_cams: Query<'w, 's, &'static CameraTimer, With<ZedCamera>>,
+    bot_cams: Query<'w, 's, &'static CameraTimer, With<BottomCamera>>,
+    zed_image: Option<Res<'w, ZedImage>>,
+    botcam_image: Option<Res<'w, BotCamImage>>,
+    export_sources: Res<'w, Assets<ImageExportSource>>,
+    images: Res<'w, Assets<Image>>,
+}
+
+impl Cameras<'_, '_> {
+    /// Cameras whose frames have a size yet
+    fn infos(&self) -> Vec<CameraInfo> {
+        let image_size = |source: &Handle<ImageExportSource>| {
+            let source = self.export_sources.get(source)?;
+            self.images.get(&source.0).map(Image::size)
+        };
+        let mut cameras = Vec::new();
+        for (kind, rate, source) in [
+            (
+                MessageKind::ZedImage,
+                self.zed_cams.iter().next().map(CameraTimer::rate),
+                self.zed_image.as_ref().map(|image| &image.0),
+            ),
+            (
+                MessageKind::BotcamImage,
+                self.bot_cams.iter().next().map(CameraTimer::rate),
+                self.botcam_image.as_ref().map(|image| &image.0),
+            ),
+        ] {
+            let (Some(rate), Some(size)) = (rate, source.and_then(image_size)) else {
+                continue;
+            };
+            cameras.push(CameraInfo {
+                kind,
+                width: size.x,
+                height: size.y,
+                rate,
+            });
+        }
+        cameras
+    }
+}
+
+/// Each kind of sensor's timer, and how often they can sample
+#[derive(SystemParam)]
+pub struct Sensors<'w, 's> {
+    imus: Query<'w, 's, &'static SensorTimer, With<Imu>>,
+    dvls: Query<'w, 's, &'static SensorTimer, With<Dvl>>,
+    depths: Query<'w, 's, &'static SensorTimer, With<DepthSensor>>,
+    ground_truth: Res<'w, GroundTruthStream>,
+    time: Res<'w, Time<Fixed>>,
+}
+
+impl Sensors<'_, '_> {
+    fn set(&self) -> SensorSet {
+        // Sensors sample at most once per tick, however fast they are set to
+        let tick_rate = 1.0 / self.time.timestep().as_secs_f32();
+        let sensor_info = |timer: &SensorTimer| SensorInfo {
+            rate: timer.rate().min(tick_rate),
+            own_message: timer.own_message,
+        };
+        SensorSet {
+            depth: self.depths.iter().next().map(sensor_info),
+            dvl: self.dvls.iter().next().map(sensor_info),
+            imu: self.imus.iter().next().map(sensor_info),
+            ground_truth: self.ground_truth.enabled(),
+        }
+    }
+}
+
+/// Keeps the capabilities advertised to the HAL in step with the scene
+pub fn update_hello(
+    outgoing: Res<Outgoing>,
+    thrusters: Query<&ThrusterOf>,
+    cameras: Cameras,
+    targets: Query<&MLTargetOf>,
+    sensors: Sensors,
+) {
+    let mut thruster_ids: Vec<_> = thrusters.iter().map(|thruster| thruster.id).collect();
+    thruster_ids.sort_unstable();
+
+    let mut ml_target_kinds: Vec<_> = targets.iter().map(|target| target.kind).collect();
+    ml_target_kinds.sort_unstable_by_key(|kind| *kind as u8);
+    ml_target_kinds.dedup();
+
+    outgoing.set_hello(Hello {
+        version: PROTOCOL_VERSION,
+        thruster_ids,
+        cameras: cameras.infos(),
+        ml_target_kinds,
+        sensors: sensors.set(),
+    });
+}
+
+pub fn check_hal_hello(
+    mut incoming: EventReader<IncomingMessage>,
+    outgoing: Res<Outgoing>,
+    mut hal_hello: Local<Option<Hello>>,
+) {
+    for message in incoming.read() {
+        let IncomingMessage::Hello(hello) = message else {
+            continue;
+        };
+        if hello.version == PROTOCOL_VERSION {
+            info!("HAL speaks protocol version {}", hello.version);
+        } else {
+            error!(
+                "HAL speaks protocol version {}, but the sim speaks version {}. \
+                 Message kinds and payload layouts may not match!",
+                hello.version, PROTOCOL_VERSION
+            );
+        }
+        *hal_hello = Some(hello.clone());
+    }
+    // Compared once the sim's own capabilities are known
+    let Some(sim_hello) = outgoing.hello() else {
+        return;
+    };
+    let Some(hal_hello) = hal_hello.take() else {
+        return;
+    };
+    for mismatch in capability_mismatches(&sim_hello, &hal_hello) {
+        warn!("{mismatch}");
+    }
+}
+
+/// Everything one side advertises that the other does not
+fn capability_mismatches(sim: &Hello, hal: &Hello) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    compare(
+        "thrusters",
+        &sim.thruster_ids,
+        &hal.thruster_ids,
+        &mut mismatches,
+    );
+    let camera_kinds = |hello: &Hello| -> Vec<_> { hello.cameras.iter().map(|c| c.kind).collect() };
+    compare(
+        "cameras",
+        &camera_kinds(sim),
+        &camera_kinds(hal),
+        &mut mismatches,
+    );
+    for expected in &hal.cameras {
+        let Some(camera) = sim.cameras.iter().find(|c| c.kind == expected.kind) else {
+            continue;
+        };
+        if (camera.width, camera.height) != (expected.width, expected.height) {
+            mismatches.push(format!(
+                "HAL expects {:?} frames at {}x{}, but the sim renders them at {}x{}",
+                camera.kind, expected.width, expected.height, camera.width, camera.height
+            ));
+        }
+    }
+    compare(
+        "ML target kinds",
+        &sim.ml_target_kinds,
+        &hal.ml_target_kinds,
+        &mut mismatches,
+    );
+    compare(
+        "sensors",
+        &sensor_names(sim.sensors),
+        &sensor_names(hal.sensors),
+        &mut mismatches,
+    );
+    for (name, sim, hal) in [
+        ("depth", sim.sensors.depth, hal.sensors.depth),
+        ("dvl", sim.sensors.dvl, hal.sensors.dvl),
+        ("imu", sim.sensors.imu, hal.sensors.imu),
+    ] {
+        let (Some(sim), Some(hal)) = (sim, hal) else {
+            continue;
+        };
+        // Rates go through a timer's duration, so they only round trip approximately
+        if (sim.rate - hal.rate).abs() > 0.01 * hal.rate {
+            mismatches.push(format!(
+                "HAL expects {name} samples at {} Hz, but the sim samples it at {} Hz",
+                hal.rate, sim.rate
+            ));
+        }
+        if sim.own_message != hal.own_message {
+            let sent = |own_message| {
+                if own_message {
+                    "on their own"
+                } else {
+                    "in Sensors"
+                }
+            };
+            mismatches.push(format!(
+                "HAL expects {name} samples {}, but the sim sends them {}",
+                sent(hal.own_message),
+                sent(sim.own_message)
+            ));
+        }
+    }
+    mismatches
+}
+
+fn compare<T: PartialEq + Debug>(what: &str, sim: &[T], hal: &[T], mismatches: &mut Vec<String>) {
+    let missing: Vec<_> = hal.iter().filter(|item| !sim.contains(item)).collect();
+    if !missing.is_empty() {
+        mismatches.push(format!(
+            "HAL expects {what} {missing:?} that the sim does not have"
+        ));
+    }
+    let unexpected: Vec<_> = sim.iter().filter(|item| !hal.contains(item)).collect();
+    if !unexpected.is_empty() {
+        mismatches.push(format!(
+            "Sim has {what} {unexpected:?} that the HAL does not expect"
+        ));
+    }
+}
+
+fn sensor_names(sensors: SensorSet) -> Vec<&'static str> {
+    [
+        (sensors.depth.is_some(), "depth"),
+        (sensors.dvl.is_some(), "dvl"),
+        (sensors.imu.is_some(), "imu"),
+        (sensors.ground_truth, "ground truth"),
+    ]
+    .into_iter()
+    .filter_map(|(present, name)| present.then_some(name))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use subsimgpt2::protocol::{CameraInfo, MLTargetKind};
+
+    use super::*;
+
+    fn sim_hello() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            thruster_ids: vec![0, 1, 2],
+            cameras: vec![CameraInfo {
+                kind: MessageKind::ZedImage,
+                width: 1280,
+                height: 480,
+                rate: 10.0,
+            }],
+            ml_target_kinds: vec![MLTargetKind::GateRed],
+            sensors: SensorSet {
+                depth: Some(SensorInfo {
+                    rate: 50.0,
+                    own_message: false,
+                }),
+                dvl: Some(SensorInfo {
+                    rate: 8.0,
+                    own_message: false,
+                }),
+                imu: Some(SensorInfo {
+                    rate: 64.0,
+                    own_message: false,
+                }),
+                ground_truth: false,
+            },
+        }
+    }
+
+    #[test]
+    fn matching_capabilities() {
+        assert!(capability_mismatches(&sim_hello(), &sim_hello()).is_empty());
+    }
+
+    #[test]
+    fn mismatched_capabilities() {
+        let mut hal = sim_hello();
+        hal.thruster_ids = vec![1, 2, 3];
+        hal.cameras[0].width = 640;
+        hal.sensors.dvl = None;
+        hal.sensors.ground_truth = true;
+        assert_eq!(
+            capability_mismatches(&sim_hello(), &hal),
+            [
+                "HAL expects thrusters [3] that the sim does not have",
+                "Sim has thrusters [0] that the HAL does not expect",
+                "HAL expects ZedImage frames at 640x480, but the sim renders them at 1280x480",
+                "HAL expects sensors [\"ground truth\"] that the sim does not have",
+                "Sim has sensors [\"dvl\"] that the HAL does not expect",
+            ]
+        );
+    }
+
+    #[test]
+    fn mismatched_sensor_rates() {
+        let mut hal = sim_hello();
+        // Close enough to be the same rate after a round trip through a timer
+        hal.sensors.depth.as_mut().unwrap().rate = 50.001;
+        hal.sensors.dvl.as_mut().unwrap().own_message = true;
+        hal.sensors.imu.as_mut().unwrap().rate = 200.0;
+        assert_eq!(
+            capability_mismatches(&sim_hello(), &hal),
+            [
+                "HAL expects dvl samples on their own, but the sim sends them in Sensors",
+                "HAL expects imu samples at 200 Hz, but the sim samples it at 64 Hz",
+            ]
+        );
+    }
+}
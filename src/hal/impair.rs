@@ -0,0 +1,220 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use bevy::platform::collections::HashMap;
+use rand::{Rng as _, SeedableRng as _, rngs::StdRng};
+use subsimgpt2::protocol::MessageKind;
+
+/// Makes the link to the HAL behave more like the real vehicle's, for one message kind.
+///
+/// Parsed from comma separated `key=value` pairs, e.g. `delay-ms=20,jitter-ms=5,drop=0.01`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Impairment {
+    /// Added to every message (`delay-ms`)
+    pub delay: Duration,
+    /// Up to this much more is added at random (`jitter-ms`)
+    pub jitter: Duration,
+    /// Chance of a message being lost (`drop`)
+    pub drop: f64,
+    /// Throughput cap (`bytes-per-sec`)
+    pub bytes_per_sec: Option<f64>,
+}
+
+impl Impairment {
+    pub fn is_none(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl FromStr for Impairment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut impairment = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {pair:?}"))?;
+            let value: f64 = value
+                .parse()
+                .map_err(|e| format!("invalid {key} {value:?}: {e}"))?;
+            if !value.is_finite() || value < 0.0 {
+                return Err(format!("{key} must not be negative"));
+            }
+            let millis = || Duration::from_secs_f64(value / 1000.0);
+            match key {
+                "delay-ms" => impairment.delay = millis(),
+                "jitter-ms" => impairment.jitter = millis(),
+                "drop" if value <= 1.0 => impairment.drop = value,
+                "drop" => return Err("drop must be a probability".to_owned()),
+                "bytes-per-sec" if value > 0.0 => impairment.bytes_per_sec = Some(value),
+                "bytes-per-sec" => return Err("bytes-per-sec must be positive".to_owned()),
+                _ => {
+                    return Err(format!(
+                        "unknown key {key:?}, expected delay-ms, jitter-ms, drop or bytes-per-sec"
+                    ));
+                }
+            }
+        }
+        Ok(impairment)
+    }
+}
+
+/// Decides when, if ever, each message of one kind gets across the link.
+///
+/// Messages stay in order, like they would over TCP.
+#[derive(Debug)]
+pub struct ImpairedLink {
+    impairment: Impairment,
+    rng: StdRng,
+    /// When the last message got across
+    free_at: Option<Instant>,
+}
+
+impl ImpairedLink {
+    /// Every kind gets its own RNG, so each link's decisions don't depend on the others' traffic
+    pub fn new(impairment: Impairment, seed: u64, kind: MessageKind) -> Self {
+        Self {
+            impairment,
+            rng: StdRng::seed_from_u64(seed ^ ((kind as u64) << 56)),
+            free_at: None,
+        }
+    }
+
+    /// When a message of `bytes` sent at `sent_at` arrives, or `None` if it is lost
+    pub fn arrival(&mut self, sent_at: Instant, bytes: usize) -> Option<Instant> {
+        let Impairment {
+            delay,
+            jitter,
+            drop,
+            bytes_per_sec,
+        } = self.impairment;
+        if drop > 0.0 && self.rng.gen_bool(drop) {
+            return None;
+        }
+        let mut arrival = sent_at + delay;
+        if !jitter.is_zero() {
+            arrival += self.rng.gen_range(Duration::ZERO..=jitter);
+        }
+        if let Some(free_at) = self.free_at {
+            arrival = arrival.max(free_at);
+        }
+        if let Some(bytes_per_sec) = bytes_per_sec {
+            arrival += Duration::from_secs_f64(bytes as f64 / bytes_per_sec);
+        }
+        self.free_at = Some(arrival);
+        Some(arrival)
+    }
+}
+
+/// An [`ImpairedLink`] for every impaired message kind
+#[derive(Debug, Default)]
+pub struct ImpairedLinks {
+    links: HashMap<MessageKind, ImpairedLink>,
+}
+
+impl ImpairedLinks {
+    pub fn new(impairments: &HashMap<MessageKind, Impairment>, seed: u64) -> Self {
+        Self {
+            links: impairments
+                .iter()
+                .filter(|(_, impairment)| !impairment.is_none())
+                .map(|(&kind, &impairment)| (kind, ImpairedLink::new(impairment, seed, kind)))
+                .collect(),
+        }
+    }
+
+    /// Like [`ImpairedLink::arrival`], but unimpaired kinds arrive as soon as they are sent
+    pub fn arrival(
+        &mut self,
+        kind: MessageKind,
+        sent_at: Instant,
+        bytes: usize,
+    ) -> Option<Instant> {
+        match self.links.get_mut(&kind) {
+            Some(link) => link.arrival(sent_at, bytes),
+            None => Some(sent_at),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KIND: MessageKind = MessageKind::Sensors;
+
+    #[test]
+    fn parse() {
+        assert_eq!("".parse(), Ok(Impairment::default()));
+        assert_eq!(
+            "delay-ms=20, jitter-ms=5,drop=0.01,bytes-per-sec=2000000".parse(),
+            Ok(Impairment {
+                delay: Duration::from_millis(20),
+                jitter: Duration::from_millis(5),
+                drop: 0.01,
+                bytes_per_sec: Some(2e6),
+            })
+        );
+        assert_eq!(
+            "delay-ms=0.5".parse::<Impairment>().unwrap().delay,
+            Duration::from_micros(500)
+        );
+        for invalid in [
+            "delay-ms",
+            "delay-ms=-1",
+            "jitter-ms=inf",
+            "drop=1.5",
+            "bytes-per-sec=0",
+            "loss=0.1",
+        ] {
+            assert!(invalid.parse::<Impairment>().is_err(), "{invalid}");
+        }
+    }
+
+    fn arrivals(seed: u64, start: Instant) -> Vec<Option<Instant>> {
+        let impairment = "delay-ms=10,jitter-ms=20,drop=0.3".parse().unwrap();
+        let mut link = ImpairedLink::new(impairment, seed, KIND);
+        (0..100)
+            .map(|i| link.arrival(start + Duration::from_millis(i), 100))
+            .collect()
+    }
+
+    #[test]
+    fn fixed_seed_repeats() {
+        let start = Instant::now();
+        let arrivals = arrivals(7, start);
+        assert_eq!(arrivals, self::arrivals(7, start));
+        assert_ne!(arrivals, self::arrivals(8, start));
+
+        let dropped = arrivals.iter().filter(|arrival| arrival.is_none()).count();
+        assert!((10..50).contains(&dropped), "{dropped} of 100 dropped");
+        let arrivals: Vec<_> = arrivals.into_iter().flatten().collect();
+        assert!(arrivals.is_sorted(), "messages should stay in order");
+        assert!(arrivals[0] >= start + Duration::from_millis(10));
+    }
+
+    #[test]
+    fn bandwidth_spaces_messages() {
+        let impairment = "bytes-per-sec=1000".parse().unwrap();
+        let mut link = ImpairedLink::new(impairment, 0, KIND);
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        // Back to back, each waits for the one before to get across
+        assert_eq!(link.arrival(start, 100), Some(start + ms(100)));
+        assert_eq!(link.arrival(start, 50), Some(start + ms(150)));
+        // Sent after the link went idle, only its own size counts
+        assert_eq!(link.arrival(start + ms(500), 200), Some(start + ms(700)));
+    }
+
+    #[test]
+    fn unimpaired_kinds_pass_through() {
+        let impairments = [(KIND, "drop=1".parse().unwrap())].into_iter().collect();
+        let mut links = ImpairedLinks::new(&impairments, 0);
+        let now = Instant::now();
+        assert_eq!(links.arrival(KIND, now, 10), None);
+        assert_eq!(links.arrival(MessageKind::Dvl, now, 10), Some(now));
+    }
+}
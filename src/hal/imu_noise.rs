@@ -0,0 +1,317 @@
+use std::array;
+
+use bevy::prelude::*;
+use rand::Rng;
+use rand_distr::StandardNormal;
+
+use subsimgpt2::config::Config;
+
+use crate::sim::SimRng;
+
+use super::sensors::Imu;
+
+/// Standard gravity, for datasheets that give accelerometer figures in g
+const G: f32 = 9.80665;
+
+/// Error sources of one of the IMU's sensor triads, in SI units of what it measures (rad/s for
+/// the gyro, m/s² for the accelerometer)
+#[derive(Debug, Clone, Copy, PartialEq, Reflect)]
+#[reflect(Debug)]
+pub struct InertialNoise {
+    /// White noise density, per √s (angle or velocity random walk)
+    pub white: f32,
+    /// Deviation of the bias's slow wander
+    pub bias_instability: f32,
+    /// Seconds the bias's wander stays correlated for
+    pub bias_correlation_time: f32,
+    /// Density the bias random walks with, per √s
+    pub bias_random_walk: f32,
+    /// Deviation of each axis's scale factor error, as a fraction
+    pub scale_factor: f32,
+    /// Deviation of the coupling between each pair of axes, in radians
+    pub misalignment: f32,
+    /// Deviation of the bias the IMU turns on with
+    pub turn_on_bias: f32,
+}
+
+/// How far an [`Imu`]'s readings stray from the truth.
+///
+/// Defaults follow the IMX-5 datasheet. It gives no bias random walk, so that is left off.
+/// Disabled unless the `imu-noise` config is set, see [`ImuNoiseConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Component, Reflect)]
+#[reflect(Component, Debug)]
+#[require(ImuErrors)]
+pub struct ImuNoise {
+    pub enabled: bool,
+    pub gyro: InertialNoise,
+    pub accel: InertialNoise,
+}
+
+impl Default for ImuNoise {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            gyro: InertialNoise {
+                // 0.16 °/√hr
+                white: 0.16f32.to_radians() / 60.0,
+                // 1.5 °/hr
+                bias_instability: 1.5f32.to_radians() / 3600.0,
+                bias_correlation_time: 100.0,
+                bias_random_walk: 0.0,
+                scale_factor: 0.005,
+                misalignment: 0.1f32.to_radians(),
+                turn_on_bias: 0.2f32.to_radians(),
+            },
+            accel: InertialNoise {
+                // 0.02 m/s/√hr
+                white: 0.02 / 60.0,
+                // 19 µg
+                bias_instability: 19e-6 * G,
+                bias_correlation_time: 100.0,
+                bias_random_walk: 0.0,
+                scale_factor: 0.005,
+                misalignment: 0.1f32.to_radians(),
+                turn_on_bias: 2e-3 * G,
+            },
+        }
+    }
+}
+
+/// Whether IMUs start with their [`ImuNoise`] enabled, from the `imu-noise` config
+#[derive(Debug, Default, Clone, Copy, Resource, Reflect)]
+#[reflect(Resource, Debug)]
+pub struct ImuNoiseConfig {
+    pub enabled: bool,
+}
+
+impl ImuNoiseConfig {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Self {
+            enabled: config.get("imu-noise")?.unwrap_or(false),
+        })
+    }
+}
+
+/// The errors an IMU currently has, drawn when it turns on or its [`ImuNoise`] is edited
+#[derive(Debug, Default, Clone, Copy, Component, Reflect)]
+#[reflect(Component, Debug)]
+pub struct ImuErrors {
+    pub gyro: TriadErrors,
+    pub accel: TriadErrors,
+    /// Gyro error integrated into the reported angle, in the IMU's local frame
+    pub drift: Quat,
+}
+
+impl ImuErrors {
+    /// Draws new errors, keeping the drift built up so far
+    fn turn_on(&mut self, noise: &ImuNoise, rng: &mut impl Rng) {
+        self.gyro = TriadErrors::turn_on(&noise.gyro, rng);
+        self.accel = TriadErrors::turn_on(&noise.accel, rng);
+    }
+
+    /// Corrupts one sample of perfect readings
+    fn measure(&mut self, noise: &ImuNoise, imu: &mut Imu, rng: &mut impl Rng) {
+        let dt = imu.dt;
+        let dtheta = self.gyro.measure(&noise.gyro, imu.dtheta, dt, rng);
+        let dvel = self.accel.measure(&noise.accel, imu.dvel, dt, rng);
+        // The IMU integrates its own gyro, so the angle it reports drifts with the errors its
+        // calibration at turn-on can't take out
+        let calibrated = self.gyro.matrix * imu.dtheta + self.gyro.turn_on_bias * dt;
+        self.drift = (self.drift * Quat::from_scaled_axis(dtheta - calibrated)).normalize();
+        imu.angle *= self.drift;
+        imu.dtheta = dtheta;
+        imu.dvel = dvel;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Reflect)]
+#[reflect(Debug)]
+pub struct TriadErrors {
+    /// Scale factor and misalignment errors
+    pub matrix: Mat3,
+    pub turn_on_bias: Vec3,
+    pub instability: Vec3,
+    pub random_walk: Vec3,
+}
+
+impl TriadErrors {
+    fn turn_on(noise: &InertialNoise, rng: &mut impl Rng) -> Self {
+        let scale = normal(rng, noise.scale_factor);
+        let coupling = Mat3::from_cols_array(&array::from_fn(|i| {
+            // Diagonal entries are left to the scale factor
+            if i % 4 == 0 {
+                0.0
+            } else {
+                rng.sample::<f32, _>(StandardNormal) * noise.misalignment
+            }
+        }));
+        Self {
+            matrix: Mat3::from_diagonal(Vec3::ONE + scale) + coupling,
+            turn_on_bias: normal(rng, noise.turn_on_bias),
+            instability: normal(rng, noise.bias_instability),
+            random_walk: Vec3::ZERO,
+        }
+    }
+
+    fn bias(&self) -> Vec3 {
+        self.turn_on_bias + self.instability + self.random_walk
+    }
+
+    /// Moves the bias on by `dt`, then corrupts the increment measured over it
+    fn measure(
+        &mut self,
+        noise: &InertialNoise,
+        increment: Vec3,
+        dt: f32,
+        rng: &mut impl Rng,
+    ) -> Vec3 {
+        // First-order Gauss-Markov, which keeps the instability's deviation steady
+        if noise.bias_correlation_time > 0.0 {
+            let decay = (-dt / noise.bias_correlation_time).exp();
+            self.instability = self.instability * decay
+                + normal(rng, noise.bias_instability * (1.0 - decay * decay).sqrt());
+        } else {
+            self.instability = Vec3::ZERO;
+        }
+        self.random_walk += normal(rng, noise.bias_random_walk * dt.sqrt());
+        self.matrix * increment + self.bias() * dt + normal(rng, noise.white * dt.sqrt())
+    }
+}
+
+fn normal(rng: &mut impl Rng, deviation: f32) -> Vec3 {
+    Vec3::from_array(array::from_fn(|_| rng.sample::<f32, _>(StandardNormal))) * deviation
+}
+
+pub fn enable_imu_noise(
+    config: Res<ImuNoiseConfig>,
+    noises: Query<&mut ImuNoise, Added<ImuNoise>>,
+) {
+    for mut noise in noises {
+        noise.enabled = config.enabled;
+    }
+}
+
+pub fn apply_imu_noise(
+    imus: Query<(Ref<ImuNoise>, &mut ImuErrors, &mut Imu)>,
+    mut rng: ResMut<SimRng>,
+) {
+    let rng = &mut **rng;
+    for (noise, mut errors, mut imu) in imus {
+        if noise.is_changed() {
+            errors.turn_on(&noise, rng);
+        }
+        if noise.enabled {
+            errors.measure(&noise, &mut imu, rng);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    const QUIET: InertialNoise = InertialNoise {
+        white: 0.0,
+        bias_instability: 0.0,
+        bias_correlation_time: 0.0,
+        bias_random_walk: 0.0,
+        scale_factor: 0.0,
+        misalignment: 0.0,
+        turn_on_bias: 0.0,
+    };
+
+    fn imu() -> Imu {
+        Imu {
+            angle: Quat::from_rotation_y(0.3),
+            dtheta: Vec3::new(0.01, -0.02, 0.03),
+            dvel: Vec3::new(0.0, -0.05, 0.1),
+            dt: 0.005,
+        }
+    }
+
+    /// Readings of `samples` IMU samples, from errors drawn with `seed`
+    fn readings(noise: &ImuNoise, seed: u64, samples: usize) -> Vec<Imu> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut errors = ImuErrors::default();
+        errors.turn_on(noise, &mut rng);
+        (0..samples)
+            .map(|_| {
+                let mut imu = imu();
+                errors.measure(noise, &mut imu, &mut rng);
+                imu
+            })
+            .collect()
+    }
+
+    fn deviation(values: impl IntoIterator<Item = f32>) -> f32 {
+        let values: Vec<_> = values.into_iter().collect();
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+        variance.sqrt()
+    }
+
+    #[test]
+    fn zero_noise_is_perfect() {
+        let noise = ImuNoise {
+            enabled: true,
+            gyro: QUIET,
+            accel: QUIET,
+        };
+        for reading in readings(&noise, 0, 10) {
+            let perfect = imu();
+            assert!(reading.angle.abs_diff_eq(perfect.angle, 1e-6));
+            assert_eq!(reading.dtheta, perfect.dtheta);
+            assert_eq!(reading.dvel, perfect.dvel);
+        }
+    }
+
+    #[test]
+    fn fixed_seed_repeats() {
+        let noise = ImuNoise::default();
+        let first = readings(&noise, 3, 20);
+        let again = readings(&noise, 3, 20);
+        let other = readings(&noise, 4, 20);
+        let dtheta = |readings: &[Imu]| readings.iter().map(|imu| imu.dtheta).collect::<Vec<_>>();
+        assert_eq!(dtheta(&first), dtheta(&again));
+        assert_ne!(dtheta(&first), dtheta(&other));
+        assert_ne!(dtheta(&first)[0], imu().dtheta);
+    }
+
+    #[test]
+    fn bias_statistics() {
+        let noise = InertialNoise {
+            bias_instability: 0.01,
+            bias_correlation_time: 10.0,
+            turn_on_bias: 0.1,
+            ..QUIET
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut triads: Vec<_> = (0..2000)
+            .map(|_| TriadErrors::turn_on(&noise, &mut rng))
+            .collect();
+        let turn_on = deviation(triads.iter().flat_map(|t| t.turn_on_bias.to_array()));
+        assert!(
+            (turn_on / 0.1 - 1.0).abs() < 0.05,
+            "turn-on bias deviation {turn_on}"
+        );
+
+        // The instability wanders, but its deviation holds steady
+        for triad in &mut triads {
+            for _ in 0..50 {
+                triad.measure(&noise, Vec3::ZERO, 1.0, &mut rng);
+            }
+        }
+        let instability = deviation(triads.iter().flat_map(|t| t.instability.to_array()));
+        assert!(
+            (instability / 0.01 - 1.0).abs() < 0.05,
+            "bias instability deviation {instability}"
+        );
+        // With no other error, a still IMU measures just its bias
+        let triad = &mut triads[0];
+        let bias = triad.turn_on_bias + triad.instability;
+        let measured = triad.measure(&noise, Vec3::ZERO, 1e-6, &mut rng);
+        assert!((measured / 1e-6).abs_diff_eq(bias, 1e-3));
+    }
+}
@@ -2,42 +2,54 @@ use avian3d::prelude::{
     Collider, ColliderOf, ColliderTransform, LinearVelocity, PhysicsGizmoExt, PhysicsGizmos,
     RigidBodyColliders,
 };
-use bevy::prelude::*;
+use bevy::{platform::collections::HashSet, prelude::*};
+use subsimgpt2::{frames::Frames, protocol::IncomingMessage};
 
-use crate::sim::sub::{
-    SubControls,
-    thruster::{ThrusterOf, ThrusterTarget},
+use crate::sim::{
+    SimRng,
+    sub::{
+        ResetSub, SubControls,
+        thruster::{ThrusterOf, ThrusterTarget},
+    },
 };
 
-use super::{BottomCamera, CameraEnabled, ZedCamera, net::IncomingMessage};
+use super::{BottomCamera, CameraEnabled, CameraEncoding, ZedCamera};
 
 pub fn handle_thrusters(
     mut incoming: EventReader<IncomingMessage>,
-    thrusters: Query<(&mut ThrusterTarget, &ThrusterOf)>,
+    mut thrusters: Query<(&mut ThrusterTarget, &ThrusterOf)>,
+    mut unknown_ids: Local<HashSet<u8>>,
 ) {
-    let mut powers = None;
     for message in incoming.read() {
-        let IncomingMessage::Motors(new_speeds) = message else {
+        let IncomingMessage::Motors(thrusts) = message else {
             continue;
         };
-        powers = Some(new_speeds);
-    }
-    if let Some(powers) = powers {
-        for (mut target, info) in thrusters {
-            if (info.id as usize) < powers.len() {
-                target.target_output = powers[info.id as usize];
+        for &(id, thrust) in thrusts {
+            let mut found = false;
+            for (mut target, _) in thrusters.iter_mut().filter(|(_, info)| info.id == id) {
+                target.target_output = thrust;
+                found = true;
+            }
+            // Reported once, as the HAL sends motor commands many times a second
+            if !found && unknown_ids.insert(id) {
+                warn!("HAL sent a thrust for thruster {id}, which the sub does not have");
             }
         }
     }
 }
 
+/// What the HAL can change about a camera
+type CameraSettings = (&'static mut CameraEnabled, &'static mut CameraEncoding);
+
 pub fn handle_cameras(
     mut incoming: EventReader<IncomingMessage>,
-    bottom_cameras: Query<&mut CameraEnabled, (With<BottomCamera>, Without<ZedCamera>)>,
-    zed_cameras: Query<&mut CameraEnabled, (With<ZedCamera>, Without<BottomCamera>)>,
+    mut bottom_cameras: Query<CameraSettings, (With<BottomCamera>, Without<ZedCamera>)>,
+    mut zed_cameras: Query<CameraSettings, (With<ZedCamera>, Without<BottomCamera>)>,
 ) -> Result {
     let mut bot_cam_on = None;
     let mut zed_cam_on = None;
+    let mut bot_cam_encoding = None;
+    let mut zed_cam_encoding = None;
     for message in incoming.read() {
         match message {
             IncomingMessage::BotcamOn(new_active) => {
@@ -46,21 +58,39 @@ pub fn handle_cameras(
             IncomingMessage::ZedOn(new_active) => {
                 zed_cam_on = Some(new_active);
             }
+            IncomingMessage::BotcamEncoding(new_encoding) => {
+                bot_cam_encoding = Some(new_encoding);
+            }
+            IncomingMessage::ZedEncoding(new_encoding) => {
+                zed_cam_encoding = Some(new_encoding);
+            }
             _ => {}
         }
     }
     if let Some(new_active) = bot_cam_on {
-        for mut cam in bottom_cameras {
+        for (mut cam, _) in &mut bottom_cameras {
             info!("Setting botcam to {new_active}");
             cam.0 = *new_active;
         }
     }
     if let Some(new_active) = zed_cam_on {
-        for mut cam in zed_cameras {
+        for (mut cam, _) in &mut zed_cameras {
             info!("Setting zed to {new_active}");
             cam.0 = *new_active;
         }
     }
+    if let Some(new_encoding) = bot_cam_encoding {
+        for (_, mut encoding) in &mut bottom_cameras {
+            info!("Setting botcam encoding to {new_encoding:?}");
+            encoding.0 = *new_encoding;
+        }
+    }
+    if let Some(new_encoding) = zed_cam_encoding {
+        for (_, mut encoding) in &mut zed_cameras {
+            info!("Setting zed encoding to {new_encoding:?}");
+            encoding.0 = *new_encoding;
+        }
+    }
     Ok(())
 }
 
@@ -71,6 +101,7 @@ pub fn update_localization_estimate(
     mut incoming: EventReader<IncomingMessage>,
     mut estimate: Query<(&mut Transform, &mut LinearVelocity), With<LocalizationEstimate>>,
     mut commands: Commands,
+    frames: Res<Frames>,
 ) {
     let transforms = incoming.read().filter_map(|m| {
         let IncomingMessage::LocalizationEstimate {
@@ -81,16 +112,15 @@ pub fn update_localization_estimate(
         else {
             return None;
         };
-        let change_of_coordinates = Mat3::from_cols_array(&[1., 0., 0., 0., 0., 1., 0., -1., 0.]);
-        let translation = change_of_coordinates * *position;
-        let rotation = Quat::from_mat3(rotation);
+        let translation = frames.world_to_bevy(*position);
+        let rotation = frames.orientation_to_bevy(Quat::from_mat3(rotation));
         Some((
             Transform {
                 translation,
                 rotation,
                 scale: Vec3::ONE,
             },
-            change_of_coordinates * *velocity,
+            frames.world_to_bevy(*velocity),
         ))
     });
     let Some((new_transform, new_vel)) = transforms.last() else {
@@ -142,3 +172,39 @@ pub fn debug_localization(
 
     Ok(())
 }
+
+pub fn handle_sim_control(
+    mut incoming: EventReader<IncomingMessage>,
+    mut resets: EventWriter<ResetSub>,
+    mut rng: ResMut<SimRng>,
+    frames: Res<Frames>,
+) {
+    for message in incoming.read() {
+        match message {
+            IncomingMessage::ResetSub => {
+                resets.write(ResetSub::Start);
+            }
+            IncomingMessage::CoinFlip => {
+                resets.write(ResetSub::CoinFlip);
+            }
+            IncomingMessage::TeleportSub {
+                rotation,
+                position,
+                velocity,
+                angular_velocity,
+            } => {
+                resets.write(ResetSub::Teleport {
+                    transform: Transform::from_translation(frames.world_to_bevy(*position))
+                        .with_rotation(frames.orientation_to_bevy(Quat::from_mat3(rotation))),
+                    linear_velocity: frames.world_to_bevy(*velocity),
+                    angular_velocity: frames.world_to_bevy(*angular_velocity),
+                });
+            }
+            IncomingMessage::Seed(seed) => {
+                info!("Reseeding the sim with {seed}");
+                *rng = SimRng::from_seed(*seed);
+            }
+            _ => {}
+        }
+    }
+}
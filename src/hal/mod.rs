@@ -1,31 +1,87 @@
 mod cameras;
+mod clock;
+mod depth;
+mod dvl;
+mod ground_truth;
+mod hello;
 mod image_export;
+mod impair;
+mod imu_noise;
 mod incoming;
 mod net;
+mod net_panel;
 mod sensors;
+mod stats;
 mod target;
+mod watchdog;
 
 use avian3d::prelude::PhysicsSet;
 use bevy::{prelude::*, tasks::IoTaskPool};
+use bevy_egui::EguiPrimaryContextPass;
 use cameras::update_cam_enabled;
+use clock::{count_tick, release_ticks, skip_unsampled_ticks};
+use depth::update_depth_sensors;
+use dvl::update_dvls;
+use ground_truth::send_ground_truth;
+use hello::{check_hal_hello, update_hello};
 pub use image_export::{BotCamImage, ImageExportSource, ZedImage};
+use imu_noise::{apply_imu_noise, enable_imu_noise};
 use incoming::{
-    debug_localization, handle_cameras, handle_thrusters, update_localization_estimate,
+    debug_localization, handle_cameras, handle_sim_control, handle_thrusters,
+    update_localization_estimate,
 };
-use net::{Dvl as DvlMessage, ImuINS, ImuPIMU, OutgoingMessage, SensorMessage, send};
-use sensors::{postupdate_sensors, send_sensors, update_previous_velocities};
+use net_panel::net_panel;
+use sensors::{
+    PreintegratedImu, fixed_time_from_config, postupdate_sensors, preintegrate_imus, send_sensors,
+    tick_sensor_timers, update_previous_velocities,
+};
+use subsimgpt2::{config::Config, frames::Frames};
+use watchdog::{motor_watchdog, spawn_watchdog_ui, update_watchdog_ui};
 
-pub use cameras::{BottomCamera, CameraEnabled, CameraTimer, ZedCamera};
-pub use net::MLTargetKind;
-pub use sensors::{DepthSensor, Dvl, Imu};
+pub use cameras::{
+    BOTCAM_SIZE, BottomCamera, CameraEnabled, CameraEncoding, CameraTimer, ZED_SIZE, ZedCamera,
+};
+pub use clock::{SimClock, TimestampClock};
+pub use depth::{DepthSensor, FRESH_WATER_DENSITY, SALT_WATER_DENSITY};
+pub use dvl::Dvl;
+pub use ground_truth::GroundTruthStream;
+pub use imu_noise::{ImuErrors, ImuNoise, ImuNoiseConfig, InertialNoise, TriadErrors};
+pub use net::{DropPolicy, Monitor, Outgoing, Queue, QueueConfig, Queued};
+pub use sensors::{
+    DEPTH_RATE, DVL_RATE, EarthRotation, IMU_RATE, Imu, SensorMessages, SensorTimer,
+};
+pub use stats::{KindStats, LATENCY_BUCKETS, NetStats};
+pub use subsimgpt2::protocol::{ImageEncoding, MLTargetKind};
 pub use target::{MLTargetOf, MLTargets};
 use target::{MLTargetSizeThreshold, send_ml_targets};
+pub use watchdog::MotorWatchdog;
+
+use crate::sim::sub::{TeleopState, apply_sub_resets};
 
 #[derive(Debug, Default, Clone)]
 pub struct HalPlugin;
 
 impl Plugin for HalPlugin {
     fn build(&self, app: &mut bevy::app::App) {
+        let config = app
+            .world()
+            .get_resource::<Config>()
+            .cloned()
+            .unwrap_or_default();
+        let watchdog =
+            MotorWatchdog::from_config(&config).expect("Motor watchdog config should be valid");
+        let clock = SimClock::from_config(&config).expect("Sim clock config should be valid");
+        let earth = EarthRotation::from_config(&config).expect("Latitude should be valid");
+        let imu_noise =
+            ImuNoiseConfig::from_config(&config).expect("IMU noise config should be valid");
+        let sensor_messages = config
+            .get::<SensorMessages>("sensor-messages")
+            .expect("Sensor message config should be valid")
+            .unwrap_or_default();
+        let frames = Frames::from_config(&config).expect("Frame conventions should be valid");
+        let ground_truth =
+            GroundTruthStream::from_config(&config).expect("Ground truth config should be valid");
+        let fixed_time = fixed_time_from_config(&config).expect("Tick rate should be valid");
         app.add_plugins((
             image_export::ImageExportPlugin,
             net::NetPlugin,
@@ -35,28 +91,69 @@ impl Plugin for HalPlugin {
             Update,
             (
                 handle_thrusters,
+                // Manual control takes over from the HAL while teleoperating
+                motor_watchdog
+                    .after(handle_thrusters)
+                    .run_if(not(in_state(TeleopState::Teleop))),
+                update_watchdog_ui.after(motor_watchdog),
+                handle_sim_control.before(apply_sub_resets),
                 handle_cameras,
                 (update_localization_estimate, debug_localization).chain(),
+                update_hello,
+                check_hal_hello,
             ),
         )
         .add_systems(
             FixedPostUpdate,
             (
                 update_previous_velocities.before(PhysicsSet::Prepare),
-                postupdate_sensors.after(PhysicsSet::Sync),
+                (postupdate_sensors, update_dvls, update_depth_sensors).after(PhysicsSet::Sync),
+                (enable_imu_noise, apply_imu_noise).chain(),
+                tick_sensor_timers,
+                preintegrate_imus,
                 send_sensors,
+                skip_unsampled_ticks,
+                send_ground_truth,
             )
                 .chain(),
         )
         .add_systems(PostUpdate, send_ml_targets.after(update_cam_enabled))
+        .add_systems(
+            RunFixedMainLoop,
+            release_ticks.in_set(RunFixedMainLoopSystem::BeforeFixedMainLoop),
+        )
+        .add_systems(FixedFirst, count_tick)
+        .add_systems(EguiPrimaryContextPass, net_panel)
+        .add_systems(Startup, spawn_watchdog_ui)
         .init_resource::<MLTargetSizeThreshold>()
+        .insert_resource(fixed_time)
+        .insert_resource(watchdog)
+        .insert_resource(clock)
+        .insert_resource(ground_truth)
+        .insert_resource(frames)
+        .insert_resource(earth)
+        .insert_resource(imu_noise)
+        .insert_resource(sensor_messages)
         .register_type::<(
             MLTargets,
             MLTargetOf,
             MLTargetSizeThreshold,
             Imu,
+            ImuNoise,
+            ImuErrors,
             Dvl,
             DepthSensor,
+            MotorWatchdog,
+            SimClock,
+            GroundTruthStream,
+        )>()
+        .register_type::<(
+            Frames,
+            EarthRotation,
+            ImuNoiseConfig,
+            SensorTimer,
+            PreintegratedImu,
+            SensorMessages,
         )>();
     }
 }
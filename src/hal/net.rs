@@ -1,93 +1,622 @@
 use std::{
-    mem::forget,
-    mem::{offset_of, size_of},
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Write as _},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
         mpsc::{Receiver, channel},
     },
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
 };
 
-use crate::utils::flatten_array;
-
-use super::cameras::Image;
-use async_io::Async;
-use bevy::{prelude::*, tasks::IoTaskPool};
-use futures_lite::{AsyncReadExt, AsyncWriteExt};
-use smallvec::SmallVec;
+#[cfg(unix)]
+use super::cameras::{BOTCAM_SIZE, ZED_SIZE};
+use super::{
+    clock::SimClock,
+    impair::{ImpairedLinks, Impairment},
+    stats::{NetStats, warn_dropped},
+};
+use async_channel::{Receiver as AsyncReceiver, Sender, TrySendError};
+use async_io::{Async, Timer as AsyncTimer};
+use bevy::{
+    platform::collections::HashMap, prelude::*, render::RenderApp, tasks::IoTaskPool,
+    time::common_conditions::on_real_timer,
+};
+use futures_lite::{AsyncRead, AsyncWrite, AsyncWriteExt as _, future};
+use subsimgpt2::{
+    config::Config,
+    protocol::{
+        Hello, ImageEncoding, ImageMessage, IncomingMessage, Message, MessageKind, OutgoingMessage,
+        SIM_CONNECTION_KINDS, Stamp,
+        log::LogRecord,
+        read_frame,
+        shm::{self, ShmImage, ShmRingWriter},
+        write_frame,
+    },
+};
 
 #[derive(Debug, Default, Clone)]
 pub struct NetPlugin;
 
 impl Plugin for NetPlugin {
     fn build(&self, app: &mut App) {
+        let config = app.world().get_resource::<Config>().cloned();
+        let net_config = NetConfig::from_config(&config.unwrap_or_default())
+            .expect("HAL network config should be valid");
+        let outgoing =
+            Outgoing::spawn(&net_config).expect("Outgoing HAL connection should be set up");
         app.add_event::<IncomingMessage>()
+            .insert_resource(net_config)
+            .insert_resource(outgoing.stats.clone())
+            .insert_resource(outgoing.clone())
             .add_systems(PreUpdate, receiver)
-            .add_systems(Update, dbg_send_count);
+            .add_systems(
+                Update,
+                warn_dropped.run_if(on_real_timer(Duration::from_secs(1))),
+            );
+        // Camera frames are read back and sent from the render world
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.insert_resource(outgoing);
+        }
     }
 }
 
-fn receiver(
-    mut incoming: EventWriter<IncomingMessage>,
-    mut receiver: Local<Option<Receiver<IncomingMessage>>>,
-) -> Result {
-    once!(*receiver = Some(server()?));
-    let receiver = receiver.as_mut().unwrap();
-    while let Ok(message) = receiver.try_recv() {
-        incoming.write(message);
+/// Where and how to reach the HAL
+#[derive(Debug, Clone, Resource)]
+pub struct NetConfig {
+    /// Channel the HAL sends motor commands and camera settings over
+    pub incoming: Endpoint,
+    /// Channel sensors, ML targets and camera frames are sent to the HAL over
+    pub outgoing: Endpoint,
+    /// Log every incoming message to this file
+    pub record: Option<PathBuf>,
+    /// Read incoming messages from this log instead of the HAL
+    pub replay: Option<PathBuf>,
+    /// How messages of each outgoing kind are queued
+    pub queues: HashMap<MessageKind, QueueConfig>,
+    /// Applied to messages of each kind, in either direction
+    pub impairments: HashMap<MessageKind, Impairment>,
+    /// Seeds the randomness in [`NetConfig::impairments`]
+    pub impairment_seed: u64,
+    /// How camera frames reach the HAL
+    pub image_transport: ImageTransport,
+    /// Used with [`ImageTransport::Shm`]
+    pub shm: ShmConfig,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        Self {
+            incoming: Endpoint {
+                address: HAL_OUTGOING,
+                role: Role::Connect,
+            },
+            outgoing: Endpoint {
+                address: HAL_INCOMING,
+                role: Role::Connect,
+            },
+            record: None,
+            replay: None,
+            queues: SIM_CONNECTION_KINDS
+                .into_iter()
+                .map(|kind| (kind, QueueConfig::default_for(kind)))
+                .collect(),
+            impairments: default(),
+            impairment_seed: 0,
+            image_transport: default(),
+            shm: ShmConfig {
+                socket: shm::DEFAULT_SOCKET.into(),
+                prefix: shm::DEFAULT_PREFIX.into(),
+                slots: 4,
+            },
+        }
     }
-    Ok(())
 }
 
-static MESSAGES_STARTED: AtomicUsize = AtomicUsize::new(0);
-static MESSAGES_FINISHED: AtomicUsize = AtomicUsize::new(0);
-static MESSAGES_CANCELLED: AtomicUsize = AtomicUsize::new(0);
-
-fn dbg_send_count(mut count: Local<usize>) {
-    *count += 1;
-    if *count != 100 {
-        return;
-    }
-    let ticks = *count;
-    *count = 0;
-    // let started = MESSAGES_STARTED.swap(0, Ordering::Relaxed);
-    // let finished = MESSAGES_FINISHED.swap(0, Ordering::Relaxed);
-    // if started == finished {
-    //     return;
-    // }
-    // warn!(
-    //     "started: {}; finished: {}; ratio: {}; in {} ticks",
-    //     started,
-    //     finished,
-    //     finished as f64 / started as f64,
-    //     ticks
-    // );
-    let cancelled = MESSAGES_CANCELLED.swap(0, Ordering::Relaxed);
-    if cancelled != 0 {
-        warn!(
-            "{} messages cancelled in the last {} ticks",
-            cancelled, ticks
-        );
+impl NetConfig {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut net_config = Self::default();
+        if let Some(address) = config.get("hal-outgoing")? {
+            net_config.incoming.address = address;
+        }
+        if let Some(role) = config.get("hal-outgoing-role")? {
+            net_config.incoming.role = role;
+        }
+        if let Some(address) = config.get("hal-incoming")? {
+            net_config.outgoing.address = address;
+        }
+        if let Some(role) = config.get("hal-incoming-role")? {
+            net_config.outgoing.role = role;
+        }
+        net_config.record = config.get("record")?;
+        net_config.replay = config.get("replay")?;
+        for kind in SIM_CONNECTION_KINDS {
+            if let Some(queue) = config.get(QueueConfig::key(kind))? {
+                net_config.queues.insert(kind, queue);
+            }
+        }
+        let impairment: Option<Impairment> = config.get("impair")?;
+        for kind in (0..=u8::MAX).filter_map(|kind| MessageKind::try_from(kind).ok()) {
+            let own = match kind {
+                // `impair-seed` seeds the impairments instead, so `Seed` only follows `impair`
+                MessageKind::Seed => None,
+                kind => config.get(&format!("impair-{}", kind_name(kind)))?,
+            };
+            if let Some(impairment) = own.or(impairment) {
+                net_config.impairments.insert(kind, impairment);
+            }
+        }
+        net_config.impairment_seed = match config.get("impair-seed")? {
+            Some(seed) => seed,
+            None => config.get("seed")?.unwrap_or_default(),
+        };
+        if let Some(transport) = config.get("image-transport")? {
+            net_config.image_transport = transport;
+        }
+        if let Some(socket) = config.get("shm-socket")? {
+            net_config.shm.socket = socket;
+        }
+        if let Some(prefix) = config.get("shm-prefix")? {
+            net_config.shm.prefix = prefix;
+        }
+        if let Some(slots) = config.get("shm-slots")? {
+            net_config.shm.slots = slots;
+        }
+        Ok(net_config)
+    }
+}
+
+/// Name of a message kind in config keys
+fn kind_name(kind: MessageKind) -> &'static str {
+    match kind {
+        MessageKind::Sensors => "sensors",
+        MessageKind::BotcamImage => "botcam-image",
+        MessageKind::ZedImage => "zed-image",
+        MessageKind::MlTarget => "ml-target",
+        MessageKind::Motors => "motors",
+        MessageKind::BotcamOn => "botcam-on",
+        MessageKind::ZedOn => "zed-on",
+        MessageKind::LocalizationEstimate => "localization-estimate",
+        MessageKind::BotcamEncoding => "botcam-encoding",
+        MessageKind::ZedEncoding => "zed-encoding",
+        MessageKind::Hello => "hello",
+        MessageKind::SensorAck => "sensor-ack",
+        MessageKind::ResetSub => "reset-sub",
+        MessageKind::CoinFlip => "coin-flip",
+        MessageKind::TeleportSub => "teleport-sub",
+        MessageKind::Pause => "pause",
+        MessageKind::Resume => "resume",
+        MessageKind::Step => "step",
+        MessageKind::Seed => "seed",
+        MessageKind::ShmImage => "shm-image",
+        MessageKind::GroundTruth => "ground-truth",
+        MessageKind::Imu => "imu",
+        MessageKind::Dvl => "dvl",
+        MessageKind::Depth => "depth",
+    }
+}
+
+/// Bounds the messages of one kind waiting for their connection to the HAL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub policy: DropPolicy,
+    pub capacity: usize,
+}
+
+impl QueueConfig {
+    /// Camera frames are only useful while fresh, but the HAL should see every sensor packet
+    fn default_for(kind: MessageKind) -> Self {
+        match kind {
+            MessageKind::BotcamImage | MessageKind::ZedImage => Self {
+                policy: DropPolicy::Latest,
+                capacity: 1,
+            },
+            _ => Self {
+                policy: DropPolicy::Fifo,
+                capacity: 8,
+            },
+        }
+    }
+
+    fn key(kind: MessageKind) -> &'static str {
+        match kind {
+            MessageKind::Sensors => "sensors-queue",
+            MessageKind::MlTarget => "ml-target-queue",
+            MessageKind::BotcamImage => "botcam-queue",
+            MessageKind::ZedImage => "zed-queue",
+            MessageKind::GroundTruth => "ground-truth-queue",
+            MessageKind::Imu => "imu-queue",
+            MessageKind::Dvl => "dvl-queue",
+            MessageKind::Depth => "depth-queue",
+            _ => unreachable!("{kind:?} messages are not queued"),
+        }
+    }
+}
+
+impl FromStr for QueueConfig {
+    type Err = String;
+
+    /// Parses `<policy>:<capacity>`, e.g. `fifo:8`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (policy, capacity) = s
+            .split_once(':')
+            .ok_or("expected <fifo|latest>:<capacity>")?;
+        let capacity = capacity.parse().map_err(|e| format!("{e}"))?;
+        if capacity == 0 {
+            return Err("capacity must be at least 1".to_owned());
+        }
+        Ok(Self {
+            policy: policy.parse()?,
+            capacity,
+        })
+    }
+}
+
+/// Which message is dropped when a full queue gets another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Messages are sent in order and the new one is dropped
+    Fifo,
+    /// The oldest queued message is dropped, so the HAL always gets the newest
+    Latest,
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fifo" => Ok(Self::Fifo),
+            "latest" => Ok(Self::Latest),
+            _ => Err("expected fifo or latest".to_owned()),
+        }
+    }
+}
+
+/// How camera frames reach the HAL
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ImageTransport {
+    /// Sent whole, like every other message
+    #[default]
+    Tcp,
+    /// Written to a shared memory ring, with a notification sent over a Unix socket
+    #[cfg(unix)]
+    Shm,
+}
+
+impl FromStr for ImageTransport {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            #[cfg(unix)]
+            "shm" => Ok(Self::Shm),
+            #[cfg(not(unix))]
+            "shm" => Err("shm needs Unix sockets, which this platform does not have"),
+            _ => Err("expected tcp or shm"),
+        }
+    }
+}
+
+/// Where camera frames go with [`ImageTransport::Shm`]. The socket is connected to or
+/// listened on like the HAL's incoming address.
+#[derive(Debug, Clone)]
+pub struct ShmConfig {
+    pub socket: PathBuf,
+    /// See [`shm::ring_path`]
+    pub prefix: PathBuf,
+    /// Frames the HAL can fall behind by before the one it is reading is overwritten
+    pub slots: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Endpoint {
+    pub address: SocketAddr,
+    pub role: Role,
+}
+
+/// Which side opens a connection
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The sim connects to the HAL
+    #[default]
+    Connect,
+    /// The sim listens for the HAL to connect
+    Listen,
+}
+
+impl FromStr for Role {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connect" => Ok(Self::Connect),
+            "listen" => Ok(Self::Listen),
+            _ => Err("expected connect or listen"),
+        }
+    }
+}
+
+/// Establishes connections for an [`Endpoint`] or Unix socket, retrying until one succeeds
+enum Link {
+    Connect(SocketAddr),
+    Listen(Async<TcpListener>),
+    #[cfg(unix)]
+    ConnectUnix(PathBuf),
+    #[cfg(unix)]
+    ListenUnix(Async<UnixListener>),
+}
+
+impl Link {
+    fn new(endpoint: &Endpoint) -> Result<Arc<Self>> {
+        Ok(Arc::new(match endpoint.role {
+            Role::Connect => Self::Connect(endpoint.address),
+            Role::Listen => Self::Listen(Async::<TcpListener>::bind(endpoint.address)?),
+        }))
+    }
+
+    #[cfg(unix)]
+    fn unix(path: &Path, role: Role) -> Result<Arc<Self>> {
+        Ok(Arc::new(match role {
+            Role::Connect => Self::ConnectUnix(path.to_owned()),
+            Role::Listen => {
+                // Left behind if the sim was killed
+                let _ = std::fs::remove_file(path);
+                Self::ListenUnix(
+                    Async::<UnixListener>::bind(path)
+                        .map_err(|e| format!("Failed to listen on {}: {e}", path.display()))?,
+                )
+            }
+        }))
+    }
+
+    async fn establish(&self) -> Stream {
+        loop {
+            let stream = match self {
+                Link::Connect(address) => {
+                    Async::<TcpStream>::connect(*address).await.map(Stream::Tcp)
+                }
+                Link::Listen(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| Stream::Tcp(stream)),
+                #[cfg(unix)]
+                Link::ConnectUnix(path) => {
+                    Async::<UnixStream>::connect(path).await.map(Stream::Unix)
+                }
+                #[cfg(unix)]
+                Link::ListenUnix(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| Stream::Unix(stream)),
+            };
+            match stream {
+                Ok(stream) => return stream,
+                Err(_) => AsyncTimer::after(RECONNECT_PERIOD).await,
+            };
+        }
+    }
+}
+
+/// A connection established by a [`Link`]
+enum Stream {
+    Tcp(Async<TcpStream>),
+    #[cfg(unix)]
+    Unix(Async<UnixStream>),
+}
+
+impl Stream {
+    /// Sends small frames right away instead of batching them
+    fn set_nodelay(&self) {
+        match self {
+            Stream::Tcp(stream) => {
+                let _ = stream.get_ref().set_nodelay(true);
+            }
+            #[cfg(unix)]
+            Stream::Unix(_) => {}
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_close(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+/// Where incoming messages are read from, and recorded to
+struct Incoming {
+    source: Source,
+    recorder: Option<BufWriter<File>>,
+}
+
+enum Source {
+    Hal {
+        /// Messages with when they make it across the impaired link
+        receiver: Receiver<(Instant, IncomingMessage)>,
+        /// Received but still held back by the link, in order of arrival
+        pending: VecDeque<(Instant, IncomingMessage)>,
+    },
+    /// Recorded messages in reverse, so the next one is popped off the end
+    Replay(Vec<LogRecord>),
+}
+
+impl Incoming {
+    fn new(config: &NetConfig, stats: &NetStats) -> Result<Self> {
+        let source = match &config.replay {
+            Some(path) => {
+                let log = std::fs::read(path)
+                    .map_err(|e| format!("Failed to read replay {}: {e}", path.display()))?;
+                let mut records = LogRecord::read_all(&log)?;
+                info!(
+                    "Replaying {} messages from {}",
+                    records.len(),
+                    path.display()
+                );
+                records.reverse();
+                Source::Replay(records)
+            }
+            None => Source::Hal {
+                receiver: server(config, stats)?,
+                pending: VecDeque::new(),
+            },
+        };
+        let recorder = match &config.record {
+            Some(path) => Some(BufWriter::new(File::create(path).map_err(|e| {
+                format!("Failed to create recording {}: {e}", path.display())
+            })?)),
+            None => None,
+        };
+        Ok(Self { source, recorder })
+    }
+
+    fn next(&mut self, tick: u64) -> Option<IncomingMessage> {
+        match &mut self.source {
+            Source::Hal { receiver, pending } => {
+                while let Ok((arrival, message)) = receiver.try_recv() {
+                    // Each kind is delayed by its own amount, so kinds can overtake each other
+                    let index = pending.partition_point(|(other, _)| *other <= arrival);
+                    pending.insert(index, (arrival, message));
+                }
+                if pending.front()?.0 > Instant::now() {
+                    return None;
+                }
+                pending.pop_front().map(|(_, message)| message)
+            }
+            Source::Replay(records) => {
+                // Messages are replayed before the tick they were originally received at
+                if records.last()?.tick > tick {
+                    return None;
+                }
+                let record = records.pop()?;
+                if records.is_empty() {
+                    info!("Replay finished");
+                }
+                Some(record.message)
+            }
+        }
+    }
+}
+
+fn receiver(
+    mut events: EventWriter<IncomingMessage>,
+    mut incoming: Local<Option<Incoming>>,
+    config: Res<NetConfig>,
+    stats: Res<NetStats>,
+    clock: Res<SimClock>,
+    time: Res<Time<Virtual>>,
+) -> Result {
+    once!(*incoming = Some(Incoming::new(&config, &stats)?));
+    let incoming = incoming.as_mut().unwrap();
+    let tick = clock.tick();
+    let mut recorded = false;
+    while let Some(message) = incoming.next(tick) {
+        if let Some(recorder) = &mut incoming.recorder {
+            let record = LogRecord {
+                tick,
+                time: time.elapsed_secs_f64(),
+                message,
+            };
+            recorder.write_all(&record.to_bytes()?)?;
+            recorded = true;
+            events.write(record.message);
+        } else {
+            events.write(message);
+        }
     }
+    if recorded {
+        // Flushed every frame, so the log survives the sim being killed
+        incoming.recorder.as_mut().unwrap().flush()?;
+    }
+    Ok(())
 }
 
 pub const HAL_INCOMING: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1817);
 pub const HAL_OUTGOING: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1818);
 
-fn server() -> Result<Receiver<IncomingMessage>> {
+fn server(config: &NetConfig, stats: &NetStats) -> Result<Receiver<(Instant, IncomingMessage)>> {
     let (tx, rx) = channel();
+    let link = Link::new(&config.incoming)?;
+    let stats = stats.clone();
+    let mut impaired = ImpairedLinks::new(&config.impairments, config.impairment_seed);
     IoTaskPool::get()
         .spawn(async move {
             loop {
-                let Ok(mut client) = Async::<TcpStream>::connect(HAL_OUTGOING).await else {
-                    continue;
-                };
+                let mut client = link.establish().await;
                 info!("Connection to HAL established");
+                stats.set_incoming_connected(true);
                 loop {
-                    match handle_connection(&mut client).await {
-                        Ok(Some(message)) => {
-                            tx.send(message).expect("Connection should not have closed");
+                    match read_frame(&mut client).await {
+                        Ok(Some(frame)) => {
+                            let received_at = Instant::now();
+                            // Length, kind and payload
+                            let bytes = 9 + frame.payload.len();
+                            if let Ok(kind) = MessageKind::try_from(frame.kind) {
+                                stats.received(kind, bytes);
+                            }
+                            match IncomingMessage::decode(frame.kind, &frame.payload) {
+                                Ok(message) => {
+                                    let Some(arrival) =
+                                        impaired.arrival(message.kind(), received_at, bytes)
+                                    else {
+                                        stats.dropped(message.kind());
+                                        continue;
+                                    };
+                                    tx.send((arrival, message))
+                                        .expect("Connection should not have closed");
+                                }
+                                // A bad frame has still been fully read, so the stream stays in sync
+                                Err(e) => warn!("Skipping frame from HAL: {}", e),
+                            }
                         }
                         Ok(None) => {
                             const WAIT_PERIOD: Duration = Duration::from_millis(1000);
@@ -100,288 +629,717 @@ fn server() -> Result<Receiver<IncomingMessage>> {
                         }
                     }
                 }
+                stats.set_incoming_connected(false);
             }
         })
         .detach();
     Ok(rx)
 }
 
-async fn handle_connection(stream: &mut Async<TcpStream>) -> Result<Option<IncomingMessage>> {
-    let mut len = [0u8; 8];
-    match stream.read_exact(&mut len).await {
-        Ok(_) => {}
-        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
-        Err(e) => return Err(e.into()),
-    }
-    let _len = u64::from_be_bytes(len);
-    let mut kind = 0u8;
-    stream.read_exact(std::array::from_mut(&mut kind)).await?;
-    let kind = MessageKind::try_from(kind)?;
-    let message = match kind {
-        MessageKind::Motors => {
-            let mut data = [[0u8; 4]; 8];
-            stream.read_exact(data.as_flattened_mut()).await?;
-            IncomingMessage::Motors(data.map(f32::from_be_bytes))
-        }
-        MessageKind::BotcamOn => {
-            let mut data = 0u8;
-            stream.read_exact(std::array::from_mut(&mut data)).await?;
-            IncomingMessage::BotcamOn(data != 0)
-        }
-        MessageKind::ZedOn => {
-            let mut data = 0u8;
-            stream.read_exact(std::array::from_mut(&mut data)).await?;
-            IncomingMessage::ZedOn(data != 0)
-        }
-        MessageKind::LocalizationEstimate => {
-            let mut data = [[0u8; 4]; 15];
-            stream.read_exact(data.as_flattened_mut()).await?;
-            let data = data.map(f32::from_be_bytes);
-            IncomingMessage::LocalizationEstimate {
-                rotation: Mat3::from_cols_slice(&data[..9]),
-                position: Vec3::from_slice(&data[9..12]),
-                velocity: Vec3::from_slice(&data[12..15]),
-            }
+/// Handle to the long-lived outgoing connections to the HAL.
+///
+/// Each outgoing [`MessageKind`] is written in order over its own connection,
+/// which is re-established whenever it drops.
+#[derive(Debug, Clone, Resource)]
+pub struct Outgoing {
+    queues: Arc<HashMap<MessageKind, Queue>>,
+    stats: NetStats,
+    /// Sent first on every new connection once set, and again whenever it changes
+    hello: Arc<RwLock<Option<Hello>>>,
+    /// Wakes each connection when the hello changes
+    hello_changed: Arc<Vec<Sender<()>>>,
+    /// Get a copy of every message sent, see [`Outgoing::monitor`]
+    monitors: Arc<RwLock<Vec<Monitor>>>,
+    /// Each camera's ring with [`ImageTransport::Shm`]
+    rings: Arc<HashMap<MessageKind, Arc<Mutex<ShmRingWriter>>>>,
+}
+
+impl Outgoing {
+    fn spawn(config: &NetConfig) -> Result<Self> {
+        let task_pool = IoTaskPool::get();
+        let link = Link::new(&config.outgoing)?;
+        let shm: Option<ShmTransport> = match config.image_transport {
+            ImageTransport::Tcp => None,
+            #[cfg(unix)]
+            ImageTransport::Shm => ShmTransport::new(config)
+                .inspect_err(|e| {
+                    warn!("Sending camera frames over TCP instead of shared memory: {e}")
+                })
+                .ok(),
+        };
+        let hello: Arc<RwLock<Option<Hello>>> = default();
+        let mut hello_changed = Vec::new();
+        let stats = NetStats::default();
+        let mut queues = HashMap::default();
+        for kind in SIM_CONNECTION_KINDS {
+            let queue: Queue = Queue::new(config.queues[&kind]);
+            let rx = queue.rx.clone();
+            let (link, ring) = match &shm {
+                Some(shm) if shm.rings.contains_key(&kind) => {
+                    (shm.link.clone(), shm.rings.get(&kind).cloned())
+                }
+                _ => (link.clone(), None),
+            };
+            let hello = hello.clone();
+            let (changed_tx, changed) = async_channel::bounded(1);
+            hello_changed.push(changed_tx);
+            let stats = stats.clone();
+            let mut impaired = ImpairedLinks::new(&config.impairments, config.impairment_seed);
+            task_pool
+                .spawn(async move {
+                    // Encoded messages held back by the impaired link, in order of arrival, so
+                    // the queue keeps draining while they wait
+                    let mut in_flight: VecDeque<InFlight> = VecDeque::new();
+                    loop {
+                        // When listening, the HAL connects once per outgoing kind
+                        let mut client = link.establish().await;
+                        client.set_nodelay();
+                        info!("Outgoing {kind:?} connection to HAL established");
+                        // Anything queued while disconnected is stale by now
+                        while rx.try_recv().is_ok() {
+                            stats.dropped(kind);
+                        }
+                        for _ in in_flight.drain(..) {
+                            stats.dropped(kind);
+                        }
+                        // The HAL is only greeted once the scene's capabilities are known
+                        let mut sent_hello = loop {
+                            if let Some(hello) = hello.read().unwrap().clone() {
+                                break hello;
+                            }
+                            // Closed once the sim is done with its connections
+                            if changed.recv().await.is_err() {
+                                return;
+                            }
+                        };
+                        if let Err(e) =
+                            write_frame(&mut client, &OutgoingMessage::Hello(sent_hello.clone()))
+                                .await
+                        {
+                            warn!("Failed to send hello to HAL: {}", e);
+                            continue;
+                        }
+                        stats.set_connected(kind, true);
+                        loop {
+                            let next_arrival = in_flight.front().map(|sending| sending.arrival);
+                            let next = future::or(
+                                async {
+                                    // Without the sim, the queue closes too
+                                    if changed.recv().await.is_err() {
+                                        future::pending::<()>().await;
+                                    }
+                                    Next::HelloChanged
+                                },
+                                future::or(
+                                    async {
+                                        match next_arrival {
+                                            Some(arrival) => AsyncTimer::at(arrival).await,
+                                            None => future::pending().await,
+                                        };
+                                        Next::Arrived
+                                    },
+                                    async { Next::Queued(rx.recv().await.ok()) },
+                                ),
+                            )
+                            .await;
+                            let message = match next {
+                                Next::Queued(Some(message)) => message,
+                                Next::Queued(None) => return,
+                                Next::HelloChanged => {
+                                    let current = hello.read().unwrap().clone();
+                                    let Some(current) =
+                                        current.filter(|hello| *hello != sent_hello)
+                                    else {
+                                        continue;
+                                    };
+                                    let message = OutgoingMessage::Hello(current.clone());
+                                    if let Err(e) = write_frame(&mut client, &message).await {
+                                        stats.set_connected(kind, false);
+                                        warn!("Failed to send hello to HAL: {}", e);
+                                        break;
+                                    }
+                                    sent_hello = current;
+                                    continue;
+                                }
+                                Next::Arrived => {
+                                    let sending = in_flight.pop_front().unwrap();
+                                    match write_all(&mut client, &sending.frame).await {
+                                        Ok(()) => stats.sent(
+                                            kind,
+                                            sending.bytes,
+                                            sending.queued_at.elapsed(),
+                                        ),
+                                        Err(e) => {
+                                            stats.dropped(kind);
+                                            stats.set_connected(kind, false);
+                                            warn!("Failed to send {kind:?} to HAL: {}", e);
+                                            break;
+                                        }
+                                    }
+                                    continue;
+                                }
+                            };
+                            let queued_at = message.queued_at;
+                            let (frame, bytes) = match encode(message, ring.as_deref()) {
+                                Ok(encoded) => encoded,
+                                Err(e) => {
+                                    stats.dropped(kind);
+                                    warn!("Failed to encode {kind:?} for HAL: {}", e);
+                                    continue;
+                                }
+                            };
+                            let Some(arrival) = impaired.arrival(kind, queued_at, bytes) else {
+                                stats.dropped(kind);
+                                continue;
+                            };
+                            // Arrivals of one kind never go backwards, so this stays in order
+                            in_flight.push_back(InFlight {
+                                arrival,
+                                queued_at,
+                                frame,
+                                bytes,
+                            });
+                        }
+                    }
+                })
+                .detach();
+            queues.insert(kind, queue);
+        }
+        Ok(Self {
+            queues: Arc::new(queues),
+            stats,
+            hello,
+            hello_changed: Arc::new(hello_changed),
+            monitors: default(),
+            rings: Arc::new(shm.map(|shm| shm.rings).unwrap_or_default()),
+        })
+    }
+
+    /// Updates the capabilities advertised to the HAL, greeting it on every connection that was
+    /// waiting for them and re-sending them on the rest
+    pub fn set_hello(&self, hello: Hello) {
+        if self.hello.read().unwrap().as_ref() == Some(&hello) {
+            return;
         }
-        _ => {
-            return Err("Should not receive incoming sensors or images".into());
+        *self.hello.write().unwrap() = Some(hello);
+        for changed in self.hello_changed.iter() {
+            let _ = changed.try_send(());
         }
-    };
-    Ok(Some(message))
-}
+    }
 
-#[repr(u8)]
-pub enum MessageKind {
-    Sensors = 1,
-    BotcamImage = 2,
-    ZedImage = 3,
-    MlTarget = 4,
-    Motors = 5,
-    BotcamOn = 6,
-    ZedOn = 7,
-    LocalizationEstimate = 8,
-}
+    /// The capabilities advertised to the HAL, once known
+    pub fn hello(&self) -> Option<Hello> {
+        self.hello.read().unwrap().clone()
+    }
 
-impl TryFrom<u8> for MessageKind {
-    type Error = BevyError;
+    /// Receives a copy of every message queued from now on, whether or not it reaches the HAL.
+    ///
+    /// The copies are bounded too, keeping only the newest camera frames and dropping other
+    /// messages once too many are waiting.
+    pub fn monitor(&self) -> Monitor {
+        let monitor = Monitor::new();
+        self.monitors.write().unwrap().push(monitor.clone());
+        monitor
+    }
 
-    fn try_from(value: u8) -> Result<Self> {
-        match value {
-            1 => Ok(Self::Sensors),
-            2 => Ok(Self::BotcamImage),
-            3 => Ok(Self::ZedImage),
-            4 => Ok(Self::MlTarget),
-            5 => Ok(Self::Motors),
-            6 => Ok(Self::BotcamOn),
-            7 => Ok(Self::ZedOn),
-            8 => Ok(Self::LocalizationEstimate),
-            _ => Err("Invalid message kind".into()),
+    /// Queues a message to be sent to the HAL, dropping it or an older one if its queue is full
+    pub fn send(&self, message: OutgoingMessage) {
+        self.send_compressed(message, ImageEncoding::Raw);
+    }
+
+    /// Queues a camera frame of raw RGBA8 `pixels`, compressed with `encoding` like
+    /// [`Outgoing::send_compressed`]. `camera` is [`MessageKind::BotcamImage`] or
+    /// [`MessageKind::ZedImage`].
+    ///
+    /// With [`ImageTransport::Shm`], raw frames are copied straight into the camera's ring
+    /// unless something is monitoring, which needs its own copy.
+    pub fn send_image(
+        &self,
+        camera: MessageKind,
+        stamp: Stamp,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        encoding: ImageEncoding,
+    ) {
+        let direct = encoding == ImageEncoding::Raw && self.monitors.read().unwrap().is_empty();
+        if let (true, Some(ring)) = (direct, self.rings.get(&camera)) {
+            match ring.lock().unwrap().write(pixels) {
+                Ok((slot, sequence)) => {
+                    self.send(OutgoingMessage::ShmImage(ShmImage {
+                        camera,
+                        stamp,
+                        width,
+                        height,
+                        encoding,
+                        slot,
+                        sequence,
+                        len: pixels.len() as u64,
+                    }));
+                    return;
+                }
+                Err(e) => warn!("Sending {camera:?} frame whole: {e}"),
+            }
+        }
+        let image = ImageMessage::raw(stamp, width, height, pixels.to_vec());
+        let message = match camera {
+            MessageKind::BotcamImage => OutgoingMessage::BotcamImage(image),
+            MessageKind::ZedImage => OutgoingMessage::ZedImage(image),
+            _ => unreachable!("{camera:?} is not a camera"),
+        };
+        self.send_compressed(message, encoding);
+    }
+
+    /// Like [`Outgoing::send`], but camera frames are compressed with `encoding`
+    /// on the IoTaskPool just before being written, so they never block the render world
+    pub fn send_compressed(&self, message: OutgoingMessage, encoding: ImageEncoding) {
+        let kind = match &message {
+            // Notifications wait in line with their camera's frames
+            OutgoingMessage::ShmImage(notification) => notification.camera,
+            message => message.kind(),
+        };
+        let queued = Queued {
+            message,
+            encoding,
+            queued_at: Instant::now(),
+        };
+        for monitor in self.monitors.read().unwrap().iter() {
+            monitor.push(queued.clone());
+        }
+        let Some(queue) = self.queues.get(&kind) else {
+            warn!("{kind:?} messages are not queued");
+            return;
+        };
+        self.stats.enqueued(kind);
+        for _ in 0..queue.push(queued) {
+            self.stats.dropped(kind);
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
-pub struct Dvl {
-    pub velocity_a: f32,
-    pub velocity_b: f32,
-    pub velocity_c: f32,
+const RECONNECT_PERIOD: Duration = Duration::from_millis(1000);
+
+/// Room in each ring slot beyond a raw frame, for a compressed frame's header and any rows
+/// that do not compress
+#[cfg(unix)]
+const SLOT_HEADROOM: usize = 64 << 10;
+
+/// The socket and a ring per camera for [`ImageTransport::Shm`]
+struct ShmTransport {
+    link: Arc<Link>,
+    rings: HashMap<MessageKind, Arc<Mutex<ShmRingWriter>>>,
 }
 
-impl Dvl {
-    pub fn to_be_bytes(&self) -> [u8; size_of::<Self>()] {
-        flatten_array([
-            self.velocity_a.to_be_bytes(),
-            self.velocity_b.to_be_bytes(),
-            self.velocity_c.to_be_bytes(),
-        ])
+impl ShmTransport {
+    #[cfg(unix)]
+    fn new(config: &NetConfig) -> Result<Self> {
+        let link = Link::unix(&config.shm.socket, config.outgoing.role)?;
+        let mut rings = HashMap::default();
+        for (kind, size) in [
+            (MessageKind::BotcamImage, BOTCAM_SIZE),
+            (MessageKind::ZedImage, ZED_SIZE),
+        ] {
+            let path = shm::ring_path(&config.shm.prefix, kind).expect("Cameras should have rings");
+            let slot_size = (size.x * size.y * 4) as usize + SLOT_HEADROOM;
+            let ring = ShmRingWriter::create(&path, config.shm.slots, slot_size)
+                .map_err(|e| format!("Failed to create {}: {e}", path.display()))?;
+            rings.insert(kind, Arc::new(Mutex::new(ring)));
+        }
+        Ok(Self { link, rings })
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
-pub struct ImuINS {
-    pub theta: [f32; 3],
+/// A bounded channel that drops messages as its [`DropPolicy`] says once full
+#[derive(Debug)]
+pub struct Queue<T = Queued> {
+    tx: Sender<T>,
+    /// Lets the oldest message be dropped to make room
+    rx: AsyncReceiver<T>,
+    policy: DropPolicy,
 }
 
-impl ImuINS {
-    pub fn to_be_bytes(&self) -> [u8; size_of::<Self>()] {
-        flatten_array([
-            self.theta[0].to_be_bytes(),
-            self.theta[1].to_be_bytes(),
-            self.theta[2].to_be_bytes(),
-        ])
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            rx: self.rx.clone(),
+            policy: self.policy,
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
-pub struct ImuPIMU {
-    pub dtheta: [f32; 3],
-    pub dvel: [f32; 3],
-    pub dt: f32,
-}
+impl<T> Queue<T> {
+    pub fn new(config: QueueConfig) -> Self {
+        let (tx, rx) = async_channel::bounded(config.capacity);
+        Self {
+            tx,
+            rx,
+            policy: config.policy,
+        }
+    }
+
+    /// Queues a message, returning how many messages were dropped to stay within capacity.
+    pub fn push(&self, message: T) -> usize {
+        let message = match self.tx.try_send(message) {
+            Ok(()) => return 0,
+            Err(TrySendError::Full(message)) if self.policy == DropPolicy::Latest => message,
+            Err(TrySendError::Full(_) | TrySendError::Closed(_)) => return 1,
+        };
+        let mut dropped = usize::from(self.rx.try_recv().is_ok());
+        // Another sender may have refilled the queue in the meantime
+        if self.tx.try_send(message).is_err() {
+            dropped += 1;
+        }
+        dropped
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Waits for the next message, or `None` once the queue is closed and empty
+    pub async fn recv(&self) -> Option<T> {
+        self.rx.recv().await.ok()
+    }
+
+    /// Drops every later message, while the ones already queued can still be received
+    pub fn close(&self) {
+        self.tx.close();
+    }
 
-impl ImuPIMU {
-    pub fn to_be_bytes(&self) -> [u8; size_of::<Self>()] {
-        flatten_array([
-            self.dtheta[0].to_be_bytes(),
-            self.dtheta[1].to_be_bytes(),
-            self.dtheta[2].to_be_bytes(),
-            self.dvel[0].to_be_bytes(),
-            self.dvel[1].to_be_bytes(),
-            self.dvel[2].to_be_bytes(),
-            self.dt.to_be_bytes(),
-        ])
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
-pub struct SensorMessage {
-    pub depth: f32,
-    pub dvl: Dvl,
-    pub imu_ins: ImuINS,
-    pub imu_pimu: ImuPIMU,
-}
-
-impl SensorMessage {
-    pub fn to_be_bytes(&self) -> [u8; size_of::<Self>()] {
-        let mut bytes = [0; size_of::<Self>()];
-        macro_rules! copy_field {
-            ($field:ident) => {
-                bytes[offset_of!(Self, $field)
-                    ..offset_of!(Self, $field) + size_of_val(&self.$field)]
-                    .copy_from_slice(&self.$field.to_be_bytes());
-            };
+/// A copy of every message queued to the HAL, see [`Outgoing::monitor`]
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    images: Queue,
+    others: Queue,
+}
+
+impl Monitor {
+    /// Only the newest camera frames are kept for a monitor that falls behind
+    const IMAGES: QueueConfig = QueueConfig {
+        policy: DropPolicy::Latest,
+        capacity: 4,
+    };
+    const OTHERS: QueueConfig = QueueConfig {
+        policy: DropPolicy::Fifo,
+        capacity: 1024,
+    };
+
+    fn new() -> Self {
+        Self {
+            images: Queue::new(Self::IMAGES),
+            others: Queue::new(Self::OTHERS),
         }
-        copy_field!(depth);
-        copy_field!(dvl);
-        copy_field!(imu_ins);
-        copy_field!(imu_pimu);
-        bytes
     }
-}
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Reflect)]
-#[reflect(Debug, Clone, PartialEq)]
-#[repr(u8)]
-pub enum MLTargetKind {
-    GateRed = 0,
-    GateBlue = 1,
-    #[default]
-    None = 255,
+    fn push(&self, queued: Queued) {
+        let queue = match queued.message {
+            OutgoingMessage::BotcamImage(_)
+            | OutgoingMessage::ZedImage(_)
+            | OutgoingMessage::ShmImage(_) => &self.images,
+            _ => &self.others,
+        };
+        queue.push(queued);
+    }
+
+    /// The next message queued. Camera frames are not ordered relative to other messages.
+    pub fn try_recv(&self) -> Option<Queued> {
+        self.others.try_recv().or_else(|| self.images.try_recv())
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct MLTargetData {
-    pub kind: MLTargetKind,
-    pub left: f32,
-    pub top: f32,
-    pub right: f32,
-    pub bottom: f32,
+/// What woke an outgoing connection
+enum Next {
+    HelloChanged,
+    /// The first message in flight got across
+    Arrived,
+    /// `None` once the queue is closed
+    Queued(Option<Queued>),
 }
 
-#[derive(Debug)]
-pub enum OutgoingMessage {
-    Sensors(SensorMessage),
-    BotcamImage(SystemTime, Image),
-    ZedImage(SystemTime, Image),
-    MlTarget(SmallVec<[MLTargetData; 2]>, Vec2),
+/// An encoded message on its way across an impaired link
+struct InFlight {
+    arrival: Instant,
+    queued_at: Instant,
+    frame: Vec<u8>,
+    bytes: usize,
 }
 
-impl OutgoingMessage {
-    fn kind(&self) -> MessageKind {
-        self.into()
-    }
+/// An outgoing message waiting for its connection
+#[derive(Debug, Clone)]
+pub struct Queued {
+    pub message: OutgoingMessage,
+    /// Camera frames are still raw, and compressed with this just before being written
+    pub encoding: ImageEncoding,
+    pub queued_at: Instant,
+}
 
-    fn len(&self) -> u64 {
-        (1 + match self {
-            OutgoingMessage::Sensors(sensors) => std::mem::size_of_val(sensors),
-            OutgoingMessage::BotcamImage(_, image) | OutgoingMessage::ZedImage(_, image) => {
-                size_of::<f64>() + size_of::<u32>() * 2 + size_of::<u64>() + image.buffer.len()
-            }
-            OutgoingMessage::MlTarget(targets, _size) => {
-                size_of::<u8>()
-                    + size_of::<[f32; 2]>()
-                    + (size_of::<MLTargetKind>() + size_of::<[f32; 4]>()) * targets.len()
-            }
-        }) as u64
+impl From<OutgoingMessage> for Queued {
+    fn from(message: OutgoingMessage) -> Self {
+        Self {
+            message,
+            encoding: ImageEncoding::Raw,
+            queued_at: Instant::now(),
+        }
     }
 }
 
-impl From<&OutgoingMessage> for MessageKind {
-    fn from(v: &OutgoingMessage) -> Self {
-        match v {
-            OutgoingMessage::Sensors(..) => Self::Sensors,
-            OutgoingMessage::BotcamImage(..) => Self::BotcamImage,
-            OutgoingMessage::ZedImage(..) => Self::ZedImage,
-            OutgoingMessage::MlTarget(..) => Self::MlTarget,
+/// Compresses camera frames and frames the message, ready to be written.
+///
+/// With a ring, camera frames are written to it and only a notification is framed, unless
+/// they do not fit. Also returns the bytes passed to the HAL, including any written to the ring.
+fn encode(queued: Queued, ring: Option<&Mutex<ShmRingWriter>>) -> Result<(Vec<u8>, usize)> {
+    let Queued {
+        mut message,
+        encoding,
+        ..
+    } = queued;
+    let kind = message.kind();
+    if let OutgoingMessage::BotcamImage(image) | OutgoingMessage::ZedImage(image) = &mut message {
+        image.compress(encoding)?;
+        let written = ring.map(|ring| ring.lock().unwrap().write(&image.data));
+        if let Some(Ok((slot, sequence))) = written {
+            let notification = OutgoingMessage::ShmImage(ShmImage {
+                camera: kind,
+                stamp: image.stamp,
+                width: image.width,
+                height: image.height,
+                encoding: image.encoding,
+                slot,
+                sequence,
+                len: image.data.len() as u64,
+            })
+            .to_frame()?;
+            let bytes = notification.len() + image.data.len();
+            return Ok((notification, bytes));
         }
     }
+    let frame = message.to_frame()?;
+    let bytes = match &message {
+        // Written to the ring before it was queued
+        OutgoingMessage::ShmImage(notification) => frame.len() + notification.len as usize,
+        _ => frame.len(),
+    };
+    Ok((frame, bytes))
 }
 
-#[derive(Debug, Event)]
-pub enum IncomingMessage {
-    Motors([f32; 8]),
-    BotcamOn(bool),
-    ZedOn(bool),
-    LocalizationEstimate {
-        rotation: Mat3,
-        position: Vec3,
-        velocity: Vec3,
-    },
+async fn write_all(client: &mut Stream, frame: &[u8]) -> std::io::Result<()> {
+    client.write_all(frame).await?;
+    client.flush().await
 }
 
-struct CancelCheck;
-
-impl Drop for CancelCheck {
-    fn drop(&mut self) {
-        MESSAGES_CANCELLED.fetch_add(1, Ordering::Relaxed);
-    }
-}
-
-pub async fn send(message: OutgoingMessage) -> Result {
-    MESSAGES_STARTED.fetch_add(1, Ordering::Relaxed);
-    let mut client = Async::<TcpStream>::connect(HAL_INCOMING).await?;
-    let cancel = CancelCheck;
-    client.write_all(&message.len().to_be_bytes()).await?;
-    client.write_all(&[message.kind() as u8]).await?;
-    match message {
-        OutgoingMessage::Sensors(sensors) => {
-            client.write_all(&sensors.to_be_bytes()).await?;
-        }
-        OutgoingMessage::BotcamImage(time, image) | OutgoingMessage::ZedImage(time, image) => {
-            let since_epoch = time
-                .duration_since(UNIX_EPOCH)
-                .expect("Time should not be before UNIX_EPOCH")
-                .as_secs_f64();
-
-            // TODO: image compression
-            client.write_all(&since_epoch.to_be_bytes()).await?;
-            client.write_all(&image.width.to_be_bytes()).await?;
-            client.write_all(&image.height.to_be_bytes()).await?;
-            client
-                .write_all(&(image.buffer.len() as u64).to_be_bytes())
-                .await?;
-            client.write_all(&image.buffer).await?;
-        }
-        OutgoingMessage::MlTarget(targets, size) => {
-            client.write_all(&[targets.len() as u8]).await?;
-            client.write_all(&size.x.to_be_bytes()).await?;
-            client.write_all(&size.y.to_be_bytes()).await?;
-            for target in targets {
-                client.write_all(&[target.kind as u8]).await?;
-                client.write_all(&target.left.to_be_bytes()).await?;
-                client.write_all(&target.top.to_be_bytes()).await?;
-                client.write_all(&target.right.to_be_bytes()).await?;
-                client.write_all(&target.bottom.to_be_bytes()).await?;
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use bevy::tasks::TaskPool;
+    use futures_lite::{future::block_on, io::AssertAsync};
+    use subsimgpt2::protocol::PROTOCOL_VERSION;
+
+    use super::*;
+
+    fn read_message(stream: &mut TcpStream) -> OutgoingMessage {
+        let frame = block_on(read_frame(&mut AssertAsync::new(stream)))
+            .expect("Sim should send a frame")
+            .expect("Sim should not close the connection");
+        OutgoingMessage::decode(frame.kind, &frame.payload).expect("Frame should decode")
+    }
+
+    fn depths(queue: &Queue) -> Vec<f32> {
+        std::iter::from_fn(|| queue.try_recv())
+            .map(|queued| match queued.message {
+                OutgoingMessage::Depth(_, depth) => depth,
+                message => panic!("Unexpected {message:?}"),
+            })
+            .collect()
+    }
+
+    fn depth(depth: f32) -> Queued {
+        OutgoingMessage::Depth(Stamp::default(), depth).into()
+    }
+
+    #[test]
+    fn fifo_drops_newest() {
+        let queue = Queue::new("fifo:2".parse().unwrap());
+        let dropped: Vec<_> = (1..=4).map(|i| queue.push(depth(i as f32))).collect();
+        assert_eq!(dropped, [0, 0, 1, 1]);
+        assert_eq!(depths(&queue), [1.0, 2.0]);
+    }
+
+    #[test]
+    fn latest_drops_oldest() {
+        let queue = Queue::new("latest:2".parse().unwrap());
+        let dropped: Vec<_> = (1..=4).map(|i| queue.push(depth(i as f32))).collect();
+        assert_eq!(dropped, [0, 0, 1, 1]);
+        assert_eq!(depths(&queue), [3.0, 4.0]);
+    }
+
+    #[test]
+    fn queue_config_per_kind() {
+        let config: Config = [
+            ("zed-queue", "fifo:3"),
+            ("depth-queue", "latest:2"),
+            ("imu-queue", "fifo:16"),
+        ]
+        .into_iter()
+        .collect();
+        let queues = NetConfig::from_config(&config).unwrap().queues;
+        assert_eq!(queues[&MessageKind::ZedImage], "fifo:3".parse().unwrap());
+        assert_eq!(
+            queues[&MessageKind::Depth],
+            QueueConfig {
+                policy: DropPolicy::Latest,
+                capacity: 2,
             }
+        );
+        assert_eq!(queues[&MessageKind::Imu].capacity, 16);
+        // Unset kinds keep their defaults
+        assert_eq!(
+            queues[&MessageKind::BotcamImage],
+            "latest:1".parse().unwrap()
+        );
+        assert_eq!(queues[&MessageKind::Sensors], "fifo:8".parse().unwrap());
+
+        for invalid in ["fifo", "fifo:0", "newest:1", "latest:-1"] {
+            let config: Config = [("sensors-queue", invalid)].into_iter().collect();
+            assert!(NetConfig::from_config(&config).is_err(), "{invalid}");
         }
     }
-    client.flush().await?;
-    forget(cancel);
-    MESSAGES_FINISHED.fetch_add(1, Ordering::Relaxed);
-    Ok(())
+
+    #[test]
+    fn impairment_seed_falls_back_to_seed() {
+        let seed = |values: &[(&str, &str)]| {
+            let config: Config = values.iter().copied().collect();
+            NetConfig::from_config(&config).unwrap().impairment_seed
+        };
+        assert_eq!(seed(&[]), 0);
+        assert_eq!(seed(&[("seed", "3")]), 3);
+        assert_eq!(seed(&[("seed", "3"), ("impair-seed", "5")]), 5);
+
+        let config: Config = [("impair", "drop=0.5"), ("impair-seed", "5")]
+            .into_iter()
+            .collect();
+        let impairments = NetConfig::from_config(&config).unwrap().impairments;
+        assert_eq!(impairments[&MessageKind::Seed].drop, 0.5);
+    }
+
+    #[test]
+    fn dropped_messages_are_counted() {
+        IoTaskPool::get_or_init(TaskPool::new);
+        // Nothing listens here, so messages stay queued
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = NetConfig::default();
+        config.outgoing.address = address;
+        config
+            .queues
+            .insert(MessageKind::Depth, "fifo:2".parse().unwrap());
+        let outgoing = Outgoing::spawn(&config).unwrap();
+        for i in 0..5 {
+            outgoing.send(OutgoingMessage::Depth(Stamp::default(), i as f32));
+        }
+        let stats = outgoing.stats.get(MessageKind::Depth);
+        assert_eq!(stats.enqueued, 5);
+        assert_eq!(stats.dropped, 3);
+        assert_eq!(depths(&outgoing.queues[&MessageKind::Depth]), [0.0, 1.0]);
+    }
+
+    #[test]
+    fn hello_waits_for_capabilities() {
+        IoTaskPool::get_or_init(TaskPool::new);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = NetConfig::default();
+        config.outgoing.address = listener.local_addr().unwrap();
+        let outgoing = Outgoing::spawn(&config).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // Connected before the scene's capabilities are known
+        let mut peek = [0];
+        stream.set_nonblocking(true).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(
+            stream.read(&mut peek).is_err(),
+            "Nothing should be sent yet"
+        );
+        stream.set_nonblocking(false).unwrap();
+
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            thruster_ids: vec![0, 1, 2],
+            ..default()
+        };
+        outgoing.set_hello(hello.clone());
+        assert_eq!(read_message(&mut stream), OutgoingMessage::Hello(hello));
+
+        let changed = Hello {
+            version: PROTOCOL_VERSION,
+            thruster_ids: vec![0, 1, 2, 3],
+            ..default()
+        };
+        outgoing.set_hello(changed.clone());
+        assert_eq!(read_message(&mut stream), OutgoingMessage::Hello(changed));
+    }
+
+    #[test]
+    fn delay_does_not_fill_queue() {
+        IoTaskPool::get_or_init(TaskPool::new);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = NetConfig::default();
+        config.outgoing.address = listener.local_addr().unwrap();
+        config
+            .queues
+            .insert(MessageKind::Depth, "fifo:2".parse().unwrap());
+        let delay = Duration::from_millis(300);
+        config
+            .impairments
+            .insert(MessageKind::Depth, Impairment { delay, ..default() });
+        let outgoing = Outgoing::spawn(&config).unwrap();
+        outgoing.set_hello(Hello::default());
+        let mut streams: Vec<_> = (0..SIM_CONNECTION_KINDS.len())
+            .map(|_| listener.accept().unwrap().0)
+            .collect();
+        for stream in &mut streams {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            assert_eq!(read_message(stream), OutgoingMessage::Hello(default()));
+        }
+
+        // Far more than fit in the queue are sent before the first one arrives
+        let start = Instant::now();
+        for i in 0..10 {
+            outgoing.send(OutgoingMessage::Depth(Stamp::default(), i as f32));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::thread::sleep(delay);
+        let mut peek = [0];
+        let stream = streams
+            .iter_mut()
+            .find(|stream| {
+                stream.set_nonblocking(true).unwrap();
+                let arrived = stream.peek(&mut peek).is_ok();
+                stream.set_nonblocking(false).unwrap();
+                arrived
+            })
+            .expect("Depth should have arrived");
+        let depths: Vec<_> = (0..10)
+            .map(|_| match read_message(stream) {
+                OutgoingMessage::Depth(_, depth) => depth,
+                message => panic!("Unexpected {message:?}"),
+            })
+            .collect();
+        assert!(start.elapsed() >= delay);
+        assert_eq!(depths, (0..10).map(|i| i as f32).collect::<Vec<_>>());
+        assert_eq!(outgoing.stats.get(MessageKind::Depth).dropped, 0);
+    }
 }
@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_egui::{EguiContexts, egui};
+use subsimgpt2::protocol::MessageKind;
+
+use super::{
+    net::NetConfig,
+    stats::{KindStats, LATENCY_BUCKETS, NetStats},
+    watchdog::MotorWatchdog,
+};
+
+/// How often message and byte rates are recalculated
+const RATE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Rates {
+    received: f64,
+    received_bytes: f64,
+    sent: f64,
+    bytes: f64,
+}
+
+/// Rates over the last [`RATE_PERIOD`], from the change in [`NetStats`] counters
+#[derive(Debug, Default)]
+pub struct RateTracker {
+    last: Option<(Duration, HashMap<MessageKind, KindStats>)>,
+    rates: HashMap<MessageKind, Rates>,
+}
+
+impl RateTracker {
+    fn update(&mut self, stats: &NetStats, now: Duration) {
+        if let Some((last_time, _)) = &self.last
+            && now - *last_time < RATE_PERIOD
+        {
+            return;
+        }
+        let current: HashMap<_, _> = stats.kinds().map(|kind| (kind, stats.get(kind))).collect();
+        if let Some((last_time, last)) = &self.last {
+            let secs = (now - *last_time).as_secs_f64();
+            let rate = |new: u64, old: u64| (new - old) as f64 / secs;
+            self.rates = current
+                .iter()
+                .map(|(kind, new)| {
+                    let old = last[kind];
+                    let rates = Rates {
+                        received: rate(new.received, old.received),
+                        received_bytes: rate(new.received_bytes, old.received_bytes),
+                        sent: rate(new.sent, old.sent),
+                        bytes: rate(new.bytes, old.bytes),
+                    };
+                    (*kind, rates)
+                })
+                .collect();
+        }
+        self.last = Some((now, current));
+    }
+}
+
+pub fn net_panel(
+    mut contexts: EguiContexts,
+    stats: Res<NetStats>,
+    config: Res<NetConfig>,
+    watchdog: Res<MotorWatchdog>,
+    mut rates: Local<RateTracker>,
+    time: Res<Time<Virtual>>,
+    real_time: Res<Time<Real>>,
+) -> Result {
+    rates.update(&stats, real_time.elapsed());
+    let mut kinds: Vec<_> = stats.kinds().collect();
+    kinds.sort_by_key(|&kind| kind as u8);
+
+    egui::Window::new("Network")
+        .default_open(false)
+        .show(contexts.ctx_mut()?, |ui| {
+            ui.heading("Connections");
+            let incoming = match &config.replay {
+                Some(path) => format!("replaying {}", path.display()),
+                None if stats.incoming_connected() => "connected".to_owned(),
+                None => format!("waiting ({:?})", config.incoming.role),
+            };
+            ui.label(format!("HAL → sim: {incoming}"));
+            for &kind in &kinds {
+                let kind_stats = stats.get(kind);
+                if kind_stats.enqueued == 0 && !kind_stats.connected {
+                    continue;
+                }
+                let state = if kind_stats.connected {
+                    "connected"
+                } else {
+                    "waiting"
+                };
+                ui.label(format!("sim → HAL {kind:?}: {state}"));
+            }
+            let motor_age = match watchdog.last_command() {
+                Some(last_command) => {
+                    format!("{:.2} s ago", (time.elapsed() - last_command).as_secs_f32())
+                }
+                None => "never".to_owned(),
+            };
+            ui.label(format!("Last motor command: {motor_age}"));
+            if watchdog.tripped() {
+                ui.colored_label(egui::Color32::RED, "Motor watchdog tripped");
+            }
+
+            ui.separator();
+            ui.heading("Messages");
+            egui::Grid::new("net_messages")
+                .striped(true)
+                .show(ui, |ui| {
+                    for header in [
+                        "Kind", "In /s", "In kB/s", "Out /s", "Out kB/s", "Sent", "Dropped",
+                        "Latency",
+                    ] {
+                        ui.strong(header);
+                    }
+                    ui.end_row();
+                    for &kind in &kinds {
+                        let kind_stats = stats.get(kind);
+                        if kind_stats.received == 0 && kind_stats.enqueued == 0 {
+                            continue;
+                        }
+                        let rates = rates.rates.get(&kind).copied().unwrap_or_default();
+                        ui.label(format!("{kind:?}"));
+                        ui.label(format!("{:.1}", rates.received));
+                        ui.label(format!("{:.1}", rates.received_bytes / 1000.0));
+                        ui.label(format!("{:.1}", rates.sent));
+                        ui.label(format!("{:.1}", rates.bytes / 1000.0));
+                        ui.label(kind_stats.sent.to_string());
+                        ui.label(kind_stats.dropped.to_string());
+                        ui.label(format!(
+                            "{:.2} ms",
+                            kind_stats.latency.as_secs_f64() * 1000.0
+                        ));
+                        ui.end_row();
+                    }
+                });
+
+            ui.separator();
+            ui.heading("Send latency");
+            for &kind in &kinds {
+                let kind_stats = stats.get(kind);
+                if kind_stats.sent == 0 {
+                    continue;
+                }
+                ui.collapsing(format!("{kind:?}"), |ui| {
+                    latency_histogram(ui, &kind_stats);
+                });
+            }
+        });
+    Ok(())
+}
+
+fn latency_histogram(ui: &mut egui::Ui, stats: &KindStats) {
+    let most = stats
+        .latency_histogram
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    egui::Grid::new(ui.next_auto_id()).show(ui, |ui| {
+        for (bucket, &count) in stats.latency_histogram.iter().enumerate() {
+            let label = match LATENCY_BUCKETS.get(bucket) {
+                Some(bound) => format!("≤ {bound:?}"),
+                None => format!("> {:?}", LATENCY_BUCKETS[LATENCY_BUCKETS.len() - 1]),
+            };
+            ui.label(label);
+            ui.add(
+                egui::ProgressBar::new(count as f32 / most as f32)
+                    .desired_width(160.0)
+                    .text(count.to_string()),
+            );
+            ui.end_row();
+        }
+    });
+}
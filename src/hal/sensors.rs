@@ -1,62 +1,162 @@
+use std::{str::FromStr, time::Duration};
+
 use avian3d::prelude::{
-    AngularVelocity, ComputedCenterOfMass, LinearVelocity, Position, RigidBody, Rotation,
+    AngularVelocity, ComputedCenterOfMass, Gravity, LinearVelocity, Position, RigidBody, Rotation,
 };
-use bevy::{prelude::*, tasks::IoTaskPool};
+use bevy::{ecs::name::NameOrEntity, prelude::*};
 
-use crate::hal::net::{Dvl as DvlMessage, ImuINS, ImuPIMU, OutgoingMessage, SensorMessage, send};
+use subsimgpt2::{
+    config::Config,
+    frames::Frames,
+    inertial::{RigidMotion, earth_rotation},
+    protocol::{Dvl as DvlMessage, ImuINS, ImuPIMU, OutgoingMessage, SensorMessage},
+};
 
-#[derive(Debug, Default, Clone, Copy, Component, Reflect)]
-#[reflect(Component)]
-pub struct PreviousVelocity(pub Vec3);
+use crate::hal::{
+    clock::SimClock, depth::DepthSensor, dvl::Dvl, imu_noise::ImuNoise, net::Outgoing,
+};
 
+/// The parent body's velocities at its center of mass, from before the last physics step
 #[derive(Debug, Default, Clone, Copy, Component, Reflect)]
 #[reflect(Component)]
-#[require(Transform)]
-pub struct Dvl {
-    pub velocity: Vec3,
+pub struct PreviousVelocity {
+    pub linear: Vec3,
+    pub angular: Vec3,
+}
+
+pub const IMU_RATE: f32 = 200.0;
+pub const DVL_RATE: f32 = 8.0;
+pub const DEPTH_RATE: f32 = 50.0;
+
+/// The fixed tick, by default fast enough for every sensor to sample at its own rate
+pub fn fixed_time_from_config(config: &Config) -> Result<Time<Fixed>> {
+    let rate: f64 = config.get("tick-rate")?.unwrap_or(IMU_RATE.into());
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(format!("Invalid tick rate {rate}").into());
+    }
+    Ok(Time::<Fixed>::from_hz(rate))
 }
 
+/// When a sensor samples, and how its samples are sent to the HAL.
+///
+/// Sensors sample at most once per fixed tick, so rates above the tick rate are capped to it.
+#[derive(Debug, Clone, Component, Reflect)]
+#[reflect(Component, Debug)]
+pub struct SensorTimer {
+    timer: Timer,
+    /// Send samples as the sensor's own message kind, rather than together with the
+    /// other sensors.
+    /// Starts out as `sensor-messages` says.
+    pub own_message: bool,
+}
+
+impl SensorTimer {
+    pub fn from_rate(hz: f32) -> Self {
+        Self {
+            timer: Timer::new(Duration::from_secs_f32(1.0 / hz), TimerMode::Repeating),
+            own_message: false,
+        }
+    }
+
+    pub fn rate(&self) -> f32 {
+        1.0 / self.timer.duration().as_secs_f32()
+    }
+
+    /// Whether the sensor samples this tick
+    pub fn sampled(&self) -> bool {
+        self.timer.just_finished()
+    }
+}
+
+/// How sensors are sent to the HAL unless changed on their [`SensorTimer`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Resource, Reflect)]
+#[reflect(Resource, Debug)]
+pub enum SensorMessages {
+    /// Together in one `Sensors` message
+    #[default]
+    Combined,
+    /// Each as its own message kind
+    Split,
+}
+
+impl FromStr for SensorMessages {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "combined" => Ok(Self::Combined),
+            "split" => Ok(Self::Split),
+            _ => Err("expected combined or split"),
+        }
+    }
+}
+
+/// What the IMU has measured since it last sampled, in its frame at that sample
 #[derive(Debug, Default, Clone, Copy, Component, Reflect)]
 #[reflect(Component)]
-#[require(Transform, PreviousVelocity)]
-pub struct Imu {
-    pub angle: Quat,
-    pub dtheta: [f32; 3],
+pub struct PreintegratedImu {
+    pub rotation: Quat,
     pub dvel: Vec3,
     pub dt: f32,
 }
 
+/// The Earth's rotation in Bevy's frame, which the IMU only feels when a `latitude` is set
+#[derive(Debug, Default, Clone, Copy, Resource, Reflect)]
+#[reflect(Resource, Debug)]
+pub struct EarthRotation(pub Vec3);
+
+impl EarthRotation {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let Some(latitude) = config.get::<f32>("latitude")? else {
+            return Ok(Self::default());
+        };
+        if !(-90.0..=90.0).contains(&latitude) {
+            return Err(format!("Invalid latitude {latitude}").into());
+        }
+        Ok(Self(earth_rotation(latitude)))
+    }
+}
+
+/// Readings are kept in Bevy's frame, and converted by [`Frames`] when sent to the HAL.
+///
+/// They are perfect until [`ImuNoise`] is applied to them.
 #[derive(Debug, Default, Clone, Copy, Component, Reflect)]
 #[reflect(Component)]
-#[require(Transform)]
-pub struct DepthSensor {
-    pub depth: f32,
+#[require(
+    Transform,
+    PreviousVelocity,
+    ImuNoise,
+    PreintegratedImu,
+    SensorTimer::from_rate(IMU_RATE)
+)]
+pub struct Imu {
+    /// Rotation of the sub
+    pub angle: Quat,
+    /// Rotation vector over the last tick, in the IMU's local frame. What is sent covers
+    /// everything since the last sample, see [`PreintegratedImu`].
+    pub dtheta: Vec3,
+    /// Specific force integrated over the last tick, in the IMU's local frame. Reads 1 g up at rest.
+    pub dvel: Vec3,
+    pub dt: f32,
 }
 
 pub fn update_previous_velocities(
-    subs: Query<(
-        &LinearVelocity,
-        &AngularVelocity,
-        &ComputedCenterOfMass,
-        &GlobalTransform,
-    )>,
-    mut imus: Query<(&ChildOf, &GlobalTransform, &mut PreviousVelocity), With<RigidBody>>,
+    subs: Query<(&LinearVelocity, &AngularVelocity), With<RigidBody>>,
+    mut imus: Query<(&ChildOf, &mut PreviousVelocity)>,
 ) -> Result {
-    for (parent, transform, mut prev_vel) in imus.iter_mut() {
-        let (lin_vel, ang_vel, com, parent_transform) = subs.get(parent.0)?;
-        let offset = transform.translation() - *parent_transform * com.0;
-        let vel = lin_vel.0 + ang_vel.cross(offset);
-        prev_vel.0 = vel;
+    for (parent, mut prev_vel) in imus.iter_mut() {
+        let (lin_vel, ang_vel) = subs.get(parent.0)?;
+        *prev_vel = PreviousVelocity {
+            linear: lin_vel.0,
+            angular: ang_vel.0,
+        };
     }
     Ok(())
 }
 
-// TODO: Noise models
 // TODO: Relative offsets
 pub fn postupdate_sensors(
-    mut dvls: Query<(&ChildOf, &GlobalTransform, &mut Dvl)>,
     mut imus: Query<(&ChildOf, &GlobalTransform, &PreviousVelocity, &mut Imu)>,
-    mut depths: Query<(&GlobalTransform, &mut DepthSensor)>,
     subs: Query<
         (
             &LinearVelocity,
@@ -68,79 +168,159 @@ pub fn postupdate_sensors(
         ),
         With<RigidBody>,
     >,
+    gravity: Res<Gravity>,
+    earth: Res<EarthRotation>,
     time: Res<Time<Fixed>>,
 ) -> Result {
-    for (parent, transform, mut dvl) in dvls.iter_mut() {
-        let inverse_transform = transform.affine().inverse();
-        let (lin_vel, _, ang_vel, _, com, parent_transform) = subs.get(parent.0)?;
-        let offset = transform.translation() - *parent_transform * com.0;
-        let vel = lin_vel.0 + ang_vel.cross(offset);
-        dvl.velocity = inverse_transform.transform_vector3(vel);
-    }
     for (parent, transform, prev_vel, mut imu) in imus.iter_mut() {
         let inverse_transform = transform.affine().inverse();
         let (lin_vel, ang_vel, _, rot, com, parent_transform) = subs.get(parent.0)?;
-        let offset = transform.translation() - *parent_transform * com.0;
-        let vel = lin_vel.0 + ang_vel.cross(offset);
+        let lever_arm = transform.translation() - *parent_transform * com.0;
+        let motion = RigidMotion {
+            previous_velocity: prev_vel.linear,
+            velocity: lin_vel.0,
+            previous_angular_velocity: prev_vel.angular,
+            angular_velocity: ang_vel.0,
+        };
         let dt = time.delta_secs();
-        let mut rotation;
-        if let Some(axis) = ang_vel.try_normalize() {
-            let local_axis = inverse_transform.transform_vector3(axis);
-            // has angle wrapping issues, but we shouldn't be exceeding a half rotation every tick
-            let quat = Quat::from_axis_angle(local_axis, ang_vel.length() * dt);
-            rotation = quat.to_euler(EulerRot::YZX).into();
-        } else {
-            rotation = [0.0; 3];
-        }
-        rotation[0] *= -1.;
+        let force = motion.specific_force(lever_arm, gravity.0, earth.0, dt);
         *imu = Imu {
             angle: rot.0,
-            dtheta: rotation,
-            dvel: inverse_transform.transform_vector3(vel - prev_vel.0),
+            dtheta: inverse_transform.transform_vector3(motion.angular_increment(earth.0, dt)),
+            dvel: inverse_transform.transform_vector3(force * dt),
             dt,
         }
     }
-    for (pos, mut depth) in depths.iter_mut() {
-        depth.depth = -pos.translation().y;
-    }
     Ok(())
 }
 
+pub fn tick_sensor_timers(
+    timers: Query<(NameOrEntity, &mut SensorTimer)>,
+    messages: Res<SensorMessages>,
+    time: Res<Time<Fixed>>,
+) {
+    for (name, mut timer) in timers {
+        if timer.is_added() {
+            timer.own_message = *messages == SensorMessages::Split;
+            let tick_rate = 1.0 / time.timestep().as_secs_f32();
+            if timer.rate() > tick_rate {
+                warn!(
+                    "{name} is set to sample at {} Hz, but only samples once per fixed tick, \
+                     at {tick_rate} Hz",
+                    timer.rate()
+                );
+            }
+        }
+        timer.timer.tick(time.delta());
+    }
+}
+
+pub fn preintegrate_imus(imus: Query<(&Imu, &mut PreintegratedImu)>) {
+    for (imu, mut preintegrated) in imus {
+        // Each tick's increments are measured in the frame the IMU has turned to since its last
+        // sample
+        let rotation = preintegrated.rotation;
+        preintegrated.dvel += rotation * imu.dvel;
+        preintegrated.rotation = (rotation * Quat::from_scaled_axis(imu.dtheta)).normalize();
+        preintegrated.dt += imu.dt;
+    }
+}
+
 pub fn send_sensors(
-    dvl: Query<(&ChildOf, &Dvl)>,
-    imu: Query<(&ChildOf, &Imu)>,
-    depth: Query<(&ChildOf, &DepthSensor)>,
-) -> Result {
-    let (e0, dvl) = dvl.single()?;
-    let (e1, imu) = imu.single()?;
-    let (e2, depth) = depth.single()?;
-    assert_eq!(e0, e1);
-    assert_eq!(e0, e2);
-    let Imu {
-        angle,
-        dtheta,
-        dvel,
-        dt,
-    } = imu;
-    let (yaw, pitch, roll) = angle.to_euler(EulerRot::YZX);
-    let message = SensorMessage {
-        depth: depth.depth,
-        dvl: DvlMessage {
-            velocity_a: dvl.velocity.x,
-            velocity_b: dvl.velocity.y,
-            velocity_c: dvl.velocity.z,
-        },
-        imu_ins: ImuINS {
-            theta: [-pitch, roll, yaw],
-        },
-        imu_pimu: ImuPIMU {
-            dtheta: *dtheta,
-            dvel: dvel.to_array(),
-            dt: *dt,
-        },
-    };
-    IoTaskPool::get()
-        .spawn(async move { send(OutgoingMessage::Sensors(message)).await })
-        .detach();
-    Ok(())
+    imus: Query<(&SensorTimer, &Imu, &mut PreintegratedImu)>,
+    others: Query<(&SensorTimer, AnyOf<(&Dvl, &DepthSensor)>)>,
+    outgoing: Res<Outgoing>,
+    clock: Res<SimClock>,
+    frames: Res<Frames>,
+    time: Res<Time<Fixed>>,
+    mut combined: Local<SensorMessage>,
+) {
+    let stamp = clock.stamp(time.elapsed());
+    let mut send_combined = false;
+    // Increments are only sent once, but the other readings stay until they are sampled again
+    combined.imu_pimu = ImuPIMU::default();
+    for (timer, imu, mut preintegrated) in imus {
+        if !timer.sampled() {
+            continue;
+        }
+        let ins = ImuINS {
+            theta: frames.euler_from_bevy(imu.angle),
+        };
+        let pimu = ImuPIMU {
+            dtheta: frames
+                .body_from_bevy(preintegrated.rotation.to_scaled_axis())
+                .to_array(),
+            dvel: frames.body_from_bevy(preintegrated.dvel).to_array(),
+            dt: preintegrated.dt,
+        };
+        *preintegrated = default();
+        if timer.own_message {
+            outgoing.send(OutgoingMessage::Imu(stamp, ins, pimu));
+        } else {
+            (combined.imu_ins, combined.imu_pimu) = (ins, pimu);
+            send_combined = true;
+        }
+    }
+    for (timer, (dvl, depth)) in others {
+        if !timer.sampled() {
+            continue;
+        }
+        if let Some(dvl) = dvl {
+            let [velocity_a, velocity_b, velocity_c] =
+                frames.body_from_bevy(dvl.velocity).to_array();
+            let message = DvlMessage {
+                velocity_a,
+                velocity_b,
+                velocity_c,
+                altitude: dvl.altitude,
+                beam_valid: dvl.ranges.map(|range| range.is_some()),
+            };
+            if timer.own_message {
+                outgoing.send(OutgoingMessage::Dvl(stamp, message));
+            } else {
+                combined.dvl = message;
+                send_combined = true;
+            }
+        }
+        if let Some(depth) = depth {
+            if timer.own_message {
+                outgoing.send(OutgoingMessage::Depth(stamp, depth.depth));
+            } else {
+                combined.depth = depth.depth;
+                send_combined = true;
+            }
+        }
+    }
+    if send_combined {
+        combined.stamp = stamp;
+        outgoing.send(OutgoingMessage::Sensors(*combined));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    #[test]
+    fn sensors_sample_at_their_own_rate() {
+        let mut world = World::new();
+        let mut fixed = fixed_time_from_config(&Config::default()).unwrap();
+        world.init_resource::<SensorMessages>();
+        let sensors = [IMU_RATE, DEPTH_RATE, DVL_RATE]
+            .map(|rate| world.spawn(SensorTimer::from_rate(rate)).id());
+
+        let mut samples = [0; 3];
+        // One second of fixed ticks
+        for _ in 0..IMU_RATE as usize {
+            fixed.advance_by(fixed.timestep());
+            world.insert_resource(fixed);
+            world.run_system_once(tick_sensor_timers).unwrap();
+            for (sensor, samples) in sensors.iter().zip(&mut samples) {
+                *samples += usize::from(world.get::<SensorTimer>(*sensor).unwrap().sampled());
+            }
+        }
+        assert_eq!(samples, [200, 50, 8]);
+    }
 }
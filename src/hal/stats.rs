@@ -0,0 +1,160 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use bevy::{platform::collections::HashMap, prelude::*};
+use subsimgpt2::protocol::MessageKind;
+
+/// Upper bounds of the send latency histogram buckets, with a final bucket for anything slower
+pub const LATENCY_BUCKETS: [Duration; 8] = [
+    Duration::from_micros(250),
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+];
+
+/// Counters for the messages exchanged with the HAL, shared with the tasks doing the exchanging
+#[derive(Debug, Clone, Resource)]
+pub struct NetStats {
+    kinds: Arc<HashMap<MessageKind, KindCounters>>,
+    incoming_connected: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Default)]
+struct KindCounters {
+    received: AtomicU64,
+    received_bytes: AtomicU64,
+    connected: AtomicBool,
+    enqueued: AtomicU64,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    bytes: AtomicU64,
+    /// Summed over every sent message
+    latency_us: AtomicU64,
+    latency_histogram: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+/// A snapshot of the counters for one message kind
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KindStats {
+    pub received: u64,
+    /// Including framing
+    pub received_bytes: u64,
+    /// Whether this kind's outgoing connection is up
+    pub connected: bool,
+    pub enqueued: u64,
+    pub sent: u64,
+    /// Dropped from a full queue, when reconnecting, by a failed write, or by link impairment
+    pub dropped: u64,
+    /// Including framing
+    pub bytes: u64,
+    /// Mean time from being queued to being written
+    pub latency: Duration,
+    /// Sent messages per [`LATENCY_BUCKETS`] bucket
+    pub latency_histogram: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl Default for NetStats {
+    fn default() -> Self {
+        Self {
+            kinds: Arc::new(
+                (0..=u8::MAX)
+                    .filter_map(|kind| MessageKind::try_from(kind).ok())
+                    .map(|kind| (kind, KindCounters::default()))
+                    .collect(),
+            ),
+            incoming_connected: default(),
+        }
+    }
+}
+
+impl NetStats {
+    pub fn get(&self, kind: MessageKind) -> KindStats {
+        let counters = &self.kinds[&kind];
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let sent = load(&counters.sent);
+        KindStats {
+            received: load(&counters.received),
+            received_bytes: load(&counters.received_bytes),
+            connected: counters.connected.load(Ordering::Relaxed),
+            enqueued: load(&counters.enqueued),
+            sent,
+            dropped: load(&counters.dropped),
+            bytes: load(&counters.bytes),
+            latency: Duration::from_micros(
+                load(&counters.latency_us)
+                    .checked_div(sent)
+                    .unwrap_or_default(),
+            ),
+            latency_histogram: counters.latency_histogram.each_ref().map(load),
+        }
+    }
+
+    /// Every message kind, in no particular order
+    pub fn kinds(&self) -> impl Iterator<Item = MessageKind> + '_ {
+        self.kinds.keys().copied()
+    }
+
+    /// Whether the HAL is connected to send messages to the sim
+    pub fn incoming_connected(&self) -> bool {
+        self.incoming_connected.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_incoming_connected(&self, connected: bool) {
+        self.incoming_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub(super) fn set_connected(&self, kind: MessageKind, connected: bool) {
+        self.kinds[&kind]
+            .connected
+            .store(connected, Ordering::Relaxed);
+    }
+
+    pub(super) fn received(&self, kind: MessageKind, bytes: usize) {
+        let counters = &self.kinds[&kind];
+        counters.received.fetch_add(1, Ordering::Relaxed);
+        counters
+            .received_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn enqueued(&self, kind: MessageKind) {
+        self.kinds[&kind].enqueued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn dropped(&self, kind: MessageKind) {
+        self.kinds[&kind].dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn sent(&self, kind: MessageKind, bytes: usize, latency: Duration) {
+        let counters = &self.kinds[&kind];
+        counters.sent.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        counters
+            .latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| latency <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        counters.latency_histogram[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn warn_dropped(stats: Res<NetStats>, mut last_dropped: Local<HashMap<MessageKind, u64>>) {
+    for kind in stats.kinds() {
+        let dropped = stats.get(kind).dropped;
+        let last = last_dropped.insert(kind, dropped).unwrap_or_default();
+        if dropped > last {
+            warn!("{} {kind:?} messages dropped", dropped - last);
+        }
+    }
+}
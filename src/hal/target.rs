@@ -1,10 +1,11 @@
 use bevy::math::primitives::Cuboid;
 use bevy::prelude::*;
 use bevy::render::primitives::Frustum;
-use bevy::tasks::IoTaskPool;
 use smallvec::SmallVec;
+use subsimgpt2::protocol::{MLTargetData, MLTargetKind, OutgoingMessage};
 
-use super::net::{MLTargetData, MLTargetKind, OutgoingMessage, send};
+use super::cameras::FrameStamp;
+use super::net::Outgoing;
 
 #[derive(Debug, Clone, Copy, Component, Reflect)]
 #[reflect(Debug, Clone, Component)]
@@ -50,8 +51,9 @@ pub fn send_ml_targets(
     cameras: Query<(&Camera, &MLTargets, &GlobalTransform)>,
     targets: Query<(&MLTargetOf, &GlobalTransform)>,
     size_threshold: Res<MLTargetSizeThreshold>,
+    outgoing: Res<Outgoing>,
+    stamp: Res<FrameStamp>,
 ) -> Result {
-    let task_pool = IoTaskPool::get();
     for (cam, cam_targets, cam_transform) in cameras {
         let logical_rect = cam
             .logical_viewport_rect()
@@ -89,12 +91,13 @@ pub fn send_ml_targets(
                 bottom: aabb.max.y,
             });
         }
-        let message = OutgoingMessage::MlTarget(detections, logical_rect.size());
-        task_pool
-            .spawn(async move {
-                let _ = send(message).await;
-            })
-            .detach();
+        // Only this many fit in a message
+        detections.truncate(u8::MAX as usize);
+        outgoing.send(OutgoingMessage::MlTarget(
+            stamp.0,
+            detections,
+            logical_rect.size(),
+        ));
     }
     Ok(())
 }
@@ -0,0 +1,261 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use subsimgpt2::{config::Config, protocol::IncomingMessage};
+
+use crate::sim::sub::thruster::ThrusterTarget;
+
+/// Default time without a motor command before the thrusters are stopped
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Stops the thrusters when the HAL stops sending motor commands, like the real ESCs do.
+///
+/// Only armed once the first motor command has arrived, so the sub can be driven
+/// by hand before the HAL connects.
+#[derive(Debug, Clone, Resource, Reflect)]
+#[reflect(Debug, Clone, Resource)]
+pub struct MotorWatchdog {
+    /// Zero disables the watchdog
+    pub timeout: Duration,
+    last_command: Option<Duration>,
+    tripped: bool,
+}
+
+impl MotorWatchdog {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let timeout = config
+            .get("motor-timeout-ms")?
+            .map_or(DEFAULT_TIMEOUT, Duration::from_millis);
+        Ok(Self {
+            timeout,
+            last_command: None,
+            tripped: false,
+        })
+    }
+
+    pub fn tripped(&self) -> bool {
+        self.tripped
+    }
+
+    /// Sim time the last motor command arrived at
+    pub fn last_command(&self) -> Option<Duration> {
+        self.last_command
+    }
+}
+
+pub fn motor_watchdog(
+    mut watchdog: ResMut<MotorWatchdog>,
+    mut incoming: EventReader<IncomingMessage>,
+    thrusters: Query<&mut ThrusterTarget>,
+    // Sim time, so a paused or lockstepped sim never trips the watchdog
+    time: Res<Time<Virtual>>,
+) {
+    let now = time.elapsed();
+    if incoming
+        .read()
+        .any(|message| matches!(message, IncomingMessage::Motors(..)))
+    {
+        watchdog.last_command = Some(now);
+        if watchdog.tripped {
+            info!("Motor commands from HAL resumed");
+            watchdog.tripped = false;
+        }
+        return;
+    }
+    let Some(last_command) = watchdog.last_command else {
+        return;
+    };
+    if watchdog.timeout.is_zero() || now - last_command < watchdog.timeout {
+        return;
+    }
+    if !watchdog.tripped {
+        warn!(
+            "No motor command from HAL in {:?}, stopping thrusters",
+            watchdog.timeout
+        );
+        watchdog.tripped = true;
+    }
+    // The thrusters ease towards their target, so their output ramps down
+    for mut target in thrusters {
+        target.target_output = 0.0;
+    }
+}
+
+#[derive(Debug, Component)]
+pub struct WatchdogText;
+
+pub fn spawn_watchdog_ui(mut commands: Commands) {
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            left: Val::Px(10.0),
+            padding: UiRect::all(Val::Px(6.0)),
+            ..default()
+        },
+        BackgroundColor(Color::srgb(0.6, 0.1, 0.1)),
+        Visibility::Hidden,
+        WatchdogText,
+        children![(
+            Text::new("Motor watchdog: thrusters stopped"),
+            TextFont {
+                font_size: 20.0,
+                ..default()
+            },
+            TextColor(Color::srgb(0.9, 0.9, 0.9)),
+        )],
+    ));
+}
+
+pub fn update_watchdog_ui(
+    watchdog: Res<MotorWatchdog>,
+    mut nodes: Query<&mut Visibility, With<WatchdogText>>,
+) {
+    let visibility = if watchdog.tripped {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    for mut node in &mut nodes {
+        node.set_if_neq(visibility);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::state::app::StatesPlugin;
+    use smallvec::smallvec;
+
+    use super::*;
+    use crate::{
+        control::ControlState,
+        hal::incoming::handle_thrusters,
+        sim::sub::{TeleopState, thruster::ThrusterOf},
+    };
+
+    const STEP: Duration = Duration::from_millis(100);
+
+    struct Sim {
+        app: App,
+        thruster: Entity,
+    }
+
+    impl Sim {
+        fn new() -> Self {
+            let mut app = App::new();
+            app.add_plugins(StatesPlugin)
+                .init_state::<ControlState>()
+                .add_sub_state::<TeleopState>()
+                .init_resource::<Time<Virtual>>()
+                .add_event::<IncomingMessage>()
+                .insert_resource(MotorWatchdog::from_config(&Config::default()).unwrap())
+                // Scheduled like in the HalPlugin
+                .add_systems(
+                    Update,
+                    (
+                        handle_thrusters,
+                        motor_watchdog
+                            .after(handle_thrusters)
+                            .run_if(not(in_state(TeleopState::Teleop))),
+                    ),
+                );
+            let sub = app.world_mut().spawn_empty().id();
+            let thruster = app
+                .world_mut()
+                .spawn((ThrusterOf { sub, id: 0 }, ThrusterTarget::default()))
+                .id();
+            Self { app, thruster }
+        }
+
+        /// Runs a frame `STEP` of sim time after the last, with the HAL sending `thrust`
+        fn step(&mut self, thrust: Option<f32>) {
+            if let Some(thrust) = thrust {
+                let message = IncomingMessage::Motors(smallvec![(0, thrust)]);
+                self.app.world_mut().send_event(message);
+            }
+            self.app
+                .world_mut()
+                .resource_mut::<Time<Virtual>>()
+                .advance_by(STEP);
+            self.app.update();
+        }
+
+        fn target(&self) -> f32 {
+            self.app
+                .world()
+                .get::<ThrusterTarget>(self.thruster)
+                .unwrap()
+                .target_output
+        }
+
+        fn set_target(&mut self, target: f32) {
+            self.app
+                .world_mut()
+                .get_mut::<ThrusterTarget>(self.thruster)
+                .unwrap()
+                .target_output = target;
+        }
+
+        fn watchdog(&self) -> &MotorWatchdog {
+            self.app.world().resource::<MotorWatchdog>()
+        }
+    }
+
+    #[test]
+    fn unarmed_until_first_command() {
+        let mut sim = Sim::new();
+        sim.set_target(0.5);
+        for _ in 0..20 {
+            sim.step(None);
+        }
+        assert_eq!(sim.target(), 0.5);
+        assert_eq!(sim.watchdog().last_command(), None);
+        assert!(!sim.watchdog().tripped());
+    }
+
+    #[test]
+    fn trips_after_timeout() {
+        let mut sim = Sim::new();
+        sim.step(Some(0.8));
+        assert_eq!(sim.target(), 0.8);
+        assert!(sim.watchdog().last_command().is_some());
+
+        // 400 ms since the command, still within the 500 ms timeout
+        for _ in 0..4 {
+            sim.step(None);
+        }
+        assert_eq!(sim.target(), 0.8);
+        assert!(!sim.watchdog().tripped());
+
+        sim.step(None);
+        assert_eq!(sim.target(), 0.0);
+        assert!(sim.watchdog().tripped());
+
+        // Held at zero until the HAL is back
+        sim.set_target(0.3);
+        sim.step(None);
+        assert_eq!(sim.target(), 0.0);
+        sim.step(Some(-0.4));
+        assert_eq!(sim.target(), -0.4);
+        assert!(!sim.watchdog().tripped());
+    }
+
+    #[test]
+    fn off_while_teleoperating() {
+        let mut sim = Sim::new();
+        sim.step(Some(0.8));
+        sim.app
+            .world_mut()
+            .resource_mut::<NextState<TeleopState>>()
+            .set(TeleopState::Teleop);
+        sim.step(None);
+        let state = sim.app.world().resource::<State<TeleopState>>();
+        assert_eq!(*state.get(), TeleopState::Teleop);
+        sim.set_target(0.6);
+        for _ in 0..10 {
+            sim.step(None);
+        }
+        assert_eq!(sim.target(), 0.6);
+        assert!(!sim.watchdog().tripped());
+    }
+}
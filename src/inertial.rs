@@ -0,0 +1,54 @@
+//! What an ideal IMU riding on a rigid body measures.
+//!
+//! Every vector shares one world frame, such as Bevy's; readings are rotated into the IMU's own
+//! frame by the caller.
+
+use bevy::prelude::*;
+
+/// Rotation rate of the Earth, in rad/s
+pub const EARTH_RATE: f32 = 7.292_115e-5;
+
+/// The Earth's rotation at `latitude` degrees, in Bevy's frame with north along +X (see
+/// [`crate::frames`])
+pub fn earth_rotation(latitude: f32) -> Vec3 {
+    let latitude = latitude.to_radians();
+    EARTH_RATE * Vec3::new(latitude.cos(), latitude.sin(), 0.0)
+}
+
+/// A rigid body's velocities at its center of mass, at the start and end of a tick
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RigidMotion {
+    pub previous_velocity: Vec3,
+    pub velocity: Vec3,
+    pub previous_angular_velocity: Vec3,
+    pub angular_velocity: Vec3,
+}
+
+impl RigidMotion {
+    /// Force per unit mass on an accelerometer `lever_arm` from the center of mass, that is
+    /// every acceleration it feels except gravity's. At rest, it points up.
+    ///
+    /// The centrifugal part of the Earth's rotation is taken to be part of `gravity`.
+    pub fn specific_force(
+        &self,
+        lever_arm: Vec3,
+        gravity: Vec3,
+        earth_rotation: Vec3,
+        dt: f32,
+    ) -> Vec3 {
+        let omega = self.angular_velocity;
+        let acceleration = (self.velocity - self.previous_velocity) / dt;
+        let angular_acceleration = (omega - self.previous_angular_velocity) / dt;
+        let at_sensor = acceleration
+            + angular_acceleration.cross(lever_arm)
+            + omega.cross(omega.cross(lever_arm));
+        let velocity = self.velocity + omega.cross(lever_arm);
+        let coriolis = 2.0 * earth_rotation.cross(velocity);
+        at_sensor + coriolis - gravity
+    }
+
+    /// Rotation vector a gyro measures over the tick, including the Earth's rotation
+    pub fn angular_increment(&self, earth_rotation: Vec3, dt: f32) -> Vec3 {
+        (self.angular_velocity + earth_rotation) * dt
+    }
+}
@@ -0,0 +1,6 @@
+//! Pieces of the simulator that are useful outside of it, such as to a HAL or test harness.
+
+pub mod config;
+pub mod frames;
+pub mod inertial;
+pub mod protocol;
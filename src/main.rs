@@ -1,6 +1,7 @@
 mod control;
 mod frustum_gizmo;
 pub mod hal;
+mod recorder;
 pub mod sim;
 mod skybox;
 mod utils;
@@ -15,11 +16,15 @@ use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use control::{ControlState, ControllerPlugin};
 use frustum_gizmo::FrustumGizmoPlugin;
 use hal::HalPlugin;
+use recorder::RecorderPlugin;
 use sim::{SimPlugin, sub::TeleopState};
 use skybox::SkyboxPlugin;
+use subsimgpt2::config::Config;
 
 fn main() {
+    let config = Config::load().expect("Config should be valid");
     App::new()
+        .insert_resource(config)
         .add_plugins(DefaultPlugins)
         .add_plugins((
             bevy_framepace::FramepacePlugin,
@@ -34,6 +39,7 @@ fn main() {
             HalPlugin::default(),
             ControllerPlugin::default(),
             SimPlugin::default(),
+            RecorderPlugin::default(),
         ))
         .add_systems(Startup, ui)
         .add_systems(
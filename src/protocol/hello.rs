@@ -0,0 +1,123 @@
+use super::{DecodeError, EncodeError, MLTargetKind, MessageKind, PayloadReader, put_count};
+
+/// Protocol version and what each side has to offer
+///
+/// At most 255 thrusters, cameras and ML target kinds fit in a `Hello`, encoding more is an
+/// [`EncodeError`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Hello {
+    pub version: u16,
+    pub thruster_ids: Vec<u8>,
+    pub cameras: Vec<CameraInfo>,
+    pub ml_target_kinds: Vec<MLTargetKind>,
+    pub sensors: SensorSet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraInfo {
+    /// The kind of message this camera's frames are sent as
+    pub kind: MessageKind,
+    pub width: u32,
+    pub height: u32,
+    pub rate: f32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SensorSet {
+    pub depth: Option<SensorInfo>,
+    pub dvl: Option<SensorInfo>,
+    pub imu: Option<SensorInfo>,
+    /// Whether [`MessageKind::GroundTruth`] is sent
+    pub ground_truth: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorInfo {
+    /// Samples per second
+    pub rate: f32,
+    /// Whether samples are sent as the sensor's own message kind, rather than in
+    /// [`MessageKind::Sensors`]
+    pub own_message: bool,
+}
+
+impl SensorSet {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.push(
+            (self.depth.is_some() as u8)
+                | ((self.dvl.is_some() as u8) << 1)
+                | ((self.imu.is_some() as u8) << 2)
+                | ((self.ground_truth as u8) << 3),
+        );
+        for sensor in [self.depth, self.dvl, self.imu].into_iter().flatten() {
+            buffer.extend_from_slice(&sensor.rate.to_be_bytes());
+            buffer.push(sensor.own_message as u8);
+        }
+    }
+
+    fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        let bits = reader.u8()?;
+        let mut sensor = |bit: u8| -> Result<_, DecodeError> {
+            if bits & (1 << bit) == 0 {
+                return Ok(None);
+            }
+            Ok(Some(SensorInfo {
+                rate: reader.f32()?,
+                own_message: reader.u8()? != 0,
+            }))
+        };
+        Ok(Self {
+            depth: sensor(0)?,
+            dvl: sensor(1)?,
+            imu: sensor(2)?,
+            ground_truth: bits & (1 << 3) != 0,
+        })
+    }
+}
+
+impl Hello {
+    pub(super) fn encode_payload(&self, buffer: &mut Vec<u8>) -> Result<(), EncodeError> {
+        buffer.extend_from_slice(&self.version.to_be_bytes());
+        put_count(buffer, "thrusters", self.thruster_ids.len())?;
+        buffer.extend_from_slice(&self.thruster_ids);
+        put_count(buffer, "cameras", self.cameras.len())?;
+        for camera in &self.cameras {
+            buffer.push(camera.kind as u8);
+            buffer.extend_from_slice(&camera.width.to_be_bytes());
+            buffer.extend_from_slice(&camera.height.to_be_bytes());
+            buffer.extend_from_slice(&camera.rate.to_be_bytes());
+        }
+        put_count(buffer, "ML target kinds", self.ml_target_kinds.len())?;
+        buffer.extend(self.ml_target_kinds.iter().map(|kind| *kind as u8));
+        self.sensors.encode(buffer);
+        Ok(())
+    }
+
+    pub(super) fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        let version = reader.u16()?;
+        let thruster_count = reader.u8()?;
+        let thruster_ids = reader.bytes(thruster_count as usize)?.to_vec();
+        let camera_count = reader.u8()?;
+        let cameras = (0..camera_count)
+            .map(|_| {
+                Ok(CameraInfo {
+                    kind: MessageKind::try_from(reader.u8()?)?,
+                    width: reader.u32()?,
+                    height: reader.u32()?,
+                    rate: reader.f32()?,
+                })
+            })
+            .collect::<Result<_, DecodeError>>()?;
+        let target_kind_count = reader.u8()?;
+        let ml_target_kinds = (0..target_kind_count)
+            .map(|_| MLTargetKind::try_from(reader.u8()?))
+            .collect::<Result<_, _>>()?;
+        let sensors = SensorSet::read(reader)?;
+        Ok(Self {
+            version,
+            thruster_ids,
+            cameras,
+            ml_target_kinds,
+            sensors,
+        })
+    }
+}
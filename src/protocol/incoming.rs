@@ -0,0 +1,178 @@
+use bevy::prelude::*;
+use smallvec::SmallVec;
+
+use super::{
+    DecodeError, EncodeError, Hello, ImageEncoding, Message, MessageKind, PayloadReader,
+    expect_len, put_count, put_f32s,
+};
+
+/// Messages sent from the HAL to the sim
+#[derive(Debug, Clone, PartialEq, Event)]
+pub enum IncomingMessage {
+    /// Thrusts by thruster id. Thrusters that are not listed keep their last thrust.
+    ///
+    /// At most 255 thrusts fit in a message, encoding more is an [`EncodeError`].
+    Motors(SmallVec<[(u8, f32); 8]>),
+    BotcamOn(bool),
+    ZedOn(bool),
+    BotcamEncoding(ImageEncoding),
+    ZedEncoding(ImageEncoding),
+    /// Rotation is from the body frame to the world frame, and vectors are in the world frame.
+    /// See [`crate::frames`].
+    LocalizationEstimate {
+        rotation: Mat3,
+        position: Vec3,
+        velocity: Vec3,
+    },
+    Hello(Hello),
+    SensorAck,
+    ResetSub,
+    CoinFlip,
+    /// In the same frame as [`IncomingMessage::LocalizationEstimate`]
+    TeleportSub {
+        rotation: Mat3,
+        position: Vec3,
+        velocity: Vec3,
+        angular_velocity: Vec3,
+    },
+    Pause,
+    Resume,
+    Step(u32),
+    Seed(u64),
+}
+
+impl Message for IncomingMessage {
+    fn kind(&self) -> MessageKind {
+        match self {
+            IncomingMessage::Motors(..) => MessageKind::Motors,
+            IncomingMessage::BotcamOn(..) => MessageKind::BotcamOn,
+            IncomingMessage::ZedOn(..) => MessageKind::ZedOn,
+            IncomingMessage::BotcamEncoding(..) => MessageKind::BotcamEncoding,
+            IncomingMessage::ZedEncoding(..) => MessageKind::ZedEncoding,
+            IncomingMessage::LocalizationEstimate { .. } => MessageKind::LocalizationEstimate,
+            IncomingMessage::Hello(..) => MessageKind::Hello,
+            IncomingMessage::SensorAck => MessageKind::SensorAck,
+            IncomingMessage::ResetSub => MessageKind::ResetSub,
+            IncomingMessage::CoinFlip => MessageKind::CoinFlip,
+            IncomingMessage::TeleportSub { .. } => MessageKind::TeleportSub,
+            IncomingMessage::Pause => MessageKind::Pause,
+            IncomingMessage::Resume => MessageKind::Resume,
+            IncomingMessage::Step(..) => MessageKind::Step,
+            IncomingMessage::Seed(..) => MessageKind::Seed,
+        }
+    }
+
+    fn encode_payload(&self, buffer: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match self {
+            IncomingMessage::Motors(thrusts) => {
+                put_count(buffer, "thrusts", thrusts.len())?;
+                for (id, thrust) in thrusts {
+                    buffer.push(*id);
+                    buffer.extend_from_slice(&thrust.to_be_bytes());
+                }
+            }
+            IncomingMessage::BotcamOn(on) | IncomingMessage::ZedOn(on) => buffer.push(*on as u8),
+            IncomingMessage::BotcamEncoding(encoding) | IncomingMessage::ZedEncoding(encoding) => {
+                buffer.push(*encoding as u8)
+            }
+            IncomingMessage::LocalizationEstimate {
+                rotation,
+                position,
+                velocity,
+            } => {
+                put_f32s(buffer, &rotation.to_cols_array());
+                put_f32s(buffer, &position.to_array());
+                put_f32s(buffer, &velocity.to_array());
+            }
+            IncomingMessage::Hello(hello) => hello.encode_payload(buffer)?,
+            IncomingMessage::TeleportSub {
+                rotation,
+                position,
+                velocity,
+                angular_velocity,
+            } => {
+                put_f32s(buffer, &rotation.to_cols_array());
+                put_f32s(buffer, &position.to_array());
+                put_f32s(buffer, &velocity.to_array());
+                put_f32s(buffer, &angular_velocity.to_array());
+            }
+            IncomingMessage::Step(ticks) => buffer.extend_from_slice(&ticks.to_be_bytes()),
+            IncomingMessage::Seed(seed) => buffer.extend_from_slice(&seed.to_be_bytes()),
+            IncomingMessage::SensorAck
+            | IncomingMessage::ResetSub
+            | IncomingMessage::CoinFlip
+            | IncomingMessage::Pause
+            | IncomingMessage::Resume => {}
+        }
+        Ok(())
+    }
+
+    fn decode_payload(kind: MessageKind, payload: &[u8]) -> Result<Self, DecodeError> {
+        // Variable length kinds are checked while decoding
+        match kind {
+            MessageKind::BotcamOn
+            | MessageKind::ZedOn
+            | MessageKind::BotcamEncoding
+            | MessageKind::ZedEncoding => expect_len(kind, payload, size_of::<u8>())?,
+            MessageKind::LocalizationEstimate => expect_len(kind, payload, size_of::<[f32; 15]>())?,
+            MessageKind::TeleportSub => expect_len(kind, payload, size_of::<[f32; 18]>())?,
+            MessageKind::Step => expect_len(kind, payload, size_of::<u32>())?,
+            MessageKind::Seed => expect_len(kind, payload, size_of::<u64>())?,
+            MessageKind::SensorAck
+            | MessageKind::ResetSub
+            | MessageKind::CoinFlip
+            | MessageKind::Pause
+            | MessageKind::Resume => expect_len(kind, payload, 0)?,
+            MessageKind::Motors | MessageKind::Hello => {}
+            MessageKind::Sensors
+            | MessageKind::BotcamImage
+            | MessageKind::ZedImage
+            | MessageKind::MlTarget
+            | MessageKind::ShmImage
+            | MessageKind::GroundTruth
+            | MessageKind::Imu
+            | MessageKind::Dvl
+            | MessageKind::Depth => return Err(DecodeError::WrongDirection(kind)),
+        }
+        let mut reader = PayloadReader::new(payload);
+        let message = match kind {
+            MessageKind::Motors => {
+                let count = reader.u8()?;
+                let thrusts = (0..count)
+                    .map(|_| Ok((reader.u8()?, reader.f32()?)))
+                    .collect::<Result<_, DecodeError>>()?;
+                IncomingMessage::Motors(thrusts)
+            }
+            MessageKind::BotcamOn => IncomingMessage::BotcamOn(reader.u8()? != 0),
+            MessageKind::ZedOn => IncomingMessage::ZedOn(reader.u8()? != 0),
+            MessageKind::LocalizationEstimate => IncomingMessage::LocalizationEstimate {
+                rotation: Mat3::from_cols_array(&reader.f32s()?),
+                position: Vec3::from_array(reader.f32s()?),
+                velocity: Vec3::from_array(reader.f32s()?),
+            },
+            MessageKind::BotcamEncoding => {
+                IncomingMessage::BotcamEncoding(ImageEncoding::try_from(reader.u8()?)?)
+            }
+            MessageKind::ZedEncoding => {
+                IncomingMessage::ZedEncoding(ImageEncoding::try_from(reader.u8()?)?)
+            }
+            MessageKind::Hello => IncomingMessage::Hello(Hello::read(&mut reader)?),
+            MessageKind::SensorAck => IncomingMessage::SensorAck,
+            MessageKind::ResetSub => IncomingMessage::ResetSub,
+            MessageKind::CoinFlip => IncomingMessage::CoinFlip,
+            MessageKind::TeleportSub => IncomingMessage::TeleportSub {
+                rotation: Mat3::from_cols_array(&reader.f32s()?),
+                position: Vec3::from_array(reader.f32s()?),
+                velocity: Vec3::from_array(reader.f32s()?),
+                angular_velocity: Vec3::from_array(reader.f32s()?),
+            },
+            MessageKind::Pause => IncomingMessage::Pause,
+            MessageKind::Resume => IncomingMessage::Resume,
+            MessageKind::Step => IncomingMessage::Step(reader.u32()?),
+            MessageKind::Seed => IncomingMessage::Seed(reader.u64()?),
+            _ => unreachable!("outgoing kinds are rejected above"),
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+}
@@ -0,0 +1,50 @@
+//! Recordings of the messages received from the HAL, so a run can be replayed without it.
+//!
+//! A log is a sequence of records, each a big-endian `u64` sim tick and `f64` sim time in
+//! seconds, followed by the message as a full frame.
+
+use super::{DecodeError, EncodeError, IncomingMessage, Message, PayloadReader};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// Fixed ticks run before the message was received
+    pub tick: u64,
+    /// Sim time in seconds when the message was received
+    pub time: f64,
+    pub message: IncomingMessage,
+}
+
+impl LogRecord {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.tick.to_be_bytes());
+        bytes.extend_from_slice(&self.time.to_be_bytes());
+        bytes.extend_from_slice(&self.message.to_frame()?);
+        Ok(bytes)
+    }
+
+    /// Decodes every record in a log, in order
+    pub fn read_all(log: &[u8]) -> Result<Vec<Self>, DecodeError> {
+        let mut reader = PayloadReader::new(log);
+        let mut records = Vec::new();
+        while !reader.is_empty() {
+            let tick = reader.u64()?;
+            let time = reader.f64()?;
+            let len = reader.u64()?;
+            if len == 0 {
+                return Err(DecodeError::InvalidValue {
+                    field: "frame length",
+                    value: len,
+                });
+            }
+            let [kind] = reader.take()?;
+            let payload = reader.bytes(len as usize - 1)?;
+            records.push(Self {
+                tick,
+                time,
+                message: IncomingMessage::decode(kind, payload)?,
+            });
+        }
+        Ok(records)
+    }
+}
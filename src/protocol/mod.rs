@@ -0,0 +1,360 @@
+//! The wire protocol spoken between the simulator and the sub code's HAL.
+//!
+//! Every message is sent as a frame: a big-endian `u64` length, a [`MessageKind`] byte, then
+//! the payload. The length counts the kind byte and the payload. All numbers are big-endian.
+
+mod hello;
+mod incoming;
+pub mod log;
+mod outgoing;
+pub mod shm;
+
+use std::{fmt, io};
+
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub use hello::{CameraInfo, Hello, SensorInfo, SensorSet};
+pub use incoming::IncomingMessage;
+pub use outgoing::{
+    Dvl, GroundTruth, ImageEncoding, ImageMessage, ImuINS, ImuPIMU, MLTargetData, MLTargetKind,
+    OutgoingMessage, SensorMessage, Stamp,
+};
+
+/// Bumped whenever the framing, message kinds or payload layouts change
+pub const PROTOCOL_VERSION: u16 = 12;
+
+/// Message kinds the sim sends over their own connection to the HAL's incoming address, so that
+/// e.g. a large camera frame never holds up a sensor packet. With shared memory, the cameras'
+/// connections go to its socket instead, see [`shm::CAMERAS`].
+pub const SIM_CONNECTION_KINDS: [MessageKind; 8] = [
+    MessageKind::Sensors,
+    MessageKind::MlTarget,
+    MessageKind::BotcamImage,
+    MessageKind::ZedImage,
+    MessageKind::GroundTruth,
+    MessageKind::Imu,
+    MessageKind::Dvl,
+    MessageKind::Depth,
+];
+
+/// Frames longer than this are assumed to come from a desynchronized stream
+pub const MAX_FRAME_LEN: u64 = 16 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MessageKind {
+    Sensors = 1,
+    BotcamImage = 2,
+    ZedImage = 3,
+    MlTarget = 4,
+    /// A count, then that many thruster ids with their thrust
+    Motors = 5,
+    BotcamOn = 6,
+    ZedOn = 7,
+    LocalizationEstimate = 8,
+    BotcamEncoding = 9,
+    ZedEncoding = 10,
+    /// Sent by both sides as the first frame of every connection. The sim sends it again
+    /// whenever its capabilities change.
+    Hello = 11,
+    /// The HAL is done with the last sensor packet, releasing the next tick in lockstep mode
+    SensorAck = 12,
+    /// Puts the sub back at the start, stopped
+    ResetSub = 13,
+    /// Puts the sub back at the start, facing one of the coin-flip headings at random
+    CoinFlip = 14,
+    TeleportSub = 15,
+    Pause = 16,
+    Resume = 17,
+    /// Runs a number of fixed ticks while paused
+    Step = 18,
+    /// Reseeds the sim's random number generator
+    Seed = 19,
+    /// A camera frame waiting in shared memory, see [`shm`]
+    ShmImage = 20,
+    /// The sub's exact state, for scoring the HAL's estimator
+    GroundTruth = 21,
+    /// One IMU sample, for an IMU that is not part of [`MessageKind::Sensors`]
+    Imu = 22,
+    /// One DVL sample, for a DVL that is not part of [`MessageKind::Sensors`]
+    Dvl = 23,
+    /// One depth sample, for a depth sensor that is not part of [`MessageKind::Sensors`]
+    Depth = 24,
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, DecodeError> {
+        match value {
+            1 => Ok(Self::Sensors),
+            2 => Ok(Self::BotcamImage),
+            3 => Ok(Self::ZedImage),
+            4 => Ok(Self::MlTarget),
+            5 => Ok(Self::Motors),
+            6 => Ok(Self::BotcamOn),
+            7 => Ok(Self::ZedOn),
+            8 => Ok(Self::LocalizationEstimate),
+            9 => Ok(Self::BotcamEncoding),
+            10 => Ok(Self::ZedEncoding),
+            11 => Ok(Self::Hello),
+            12 => Ok(Self::SensorAck),
+            13 => Ok(Self::ResetSub),
+            14 => Ok(Self::CoinFlip),
+            15 => Ok(Self::TeleportSub),
+            16 => Ok(Self::Pause),
+            17 => Ok(Self::Resume),
+            18 => Ok(Self::Step),
+            19 => Ok(Self::Seed),
+            20 => Ok(Self::ShmImage),
+            21 => Ok(Self::GroundTruth),
+            22 => Ok(Self::Imu),
+            23 => Ok(Self::Dvl),
+            24 => Ok(Self::Depth),
+            _ => Err(DecodeError::UnknownKind(value)),
+        }
+    }
+}
+
+/// Why a frame could not be decoded. The frame itself was still read in full,
+/// so the stream it came from can be read on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownKind(u8),
+    /// The kind is only ever sent in the other direction
+    WrongDirection(MessageKind),
+    Length {
+        kind: MessageKind,
+        expected: usize,
+        actual: usize,
+    },
+    Truncated,
+    TrailingBytes(usize),
+    InvalidValue {
+        field: &'static str,
+        value: u64,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownKind(kind) => write!(f, "unknown message kind {kind}"),
+            DecodeError::WrongDirection(kind) => {
+                write!(f, "{kind:?} is not sent in this direction")
+            }
+            DecodeError::Length {
+                kind,
+                expected,
+                actual,
+            } => write!(f, "{kind:?} payload is {actual} bytes, expected {expected}"),
+            DecodeError::Truncated => write!(f, "payload is too short"),
+            DecodeError::TrailingBytes(count) => {
+                write!(f, "payload has {count} unexpected trailing bytes")
+            }
+            DecodeError::InvalidValue { field, value } => write!(f, "invalid {field} {value}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Why a message could not be encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// More entries than their count field can hold
+    TooMany { field: &'static str, count: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::TooMany { field, count } => {
+                write!(f, "{count} {field} do not fit in a message, at most 255 do")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// A message that can be sent over the wire in one direction
+pub trait Message: Sized {
+    fn kind(&self) -> MessageKind;
+
+    /// Appends the payload, without the length prefix or kind byte
+    fn encode_payload(&self, buffer: &mut Vec<u8>) -> Result<(), EncodeError>;
+
+    fn decode_payload(kind: MessageKind, payload: &[u8]) -> Result<Self, DecodeError>;
+
+    /// Decodes the kind byte and payload of a frame read by [`read_frame`]
+    fn decode(kind: u8, payload: &[u8]) -> Result<Self, DecodeError> {
+        Self::decode_payload(MessageKind::try_from(kind)?, payload)
+    }
+
+    /// Encodes the message as a full length-prefixed frame
+    fn to_frame(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut buffer = vec![0; size_of::<u64>()];
+        buffer.push(self.kind() as u8);
+        self.encode_payload(&mut buffer)?;
+        let len = (buffer.len() - size_of::<u64>()) as u64;
+        buffer[..size_of::<u64>()].copy_from_slice(&len.to_be_bytes());
+        Ok(buffer)
+    }
+
+    /// Decodes exactly one full length-prefixed frame
+    fn from_frame(frame: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = PayloadReader::new(frame);
+        let len = reader.u64()?;
+        let [kind] = reader.take()?;
+        let payload = reader.rest();
+        if len != payload.len() as u64 + 1 {
+            return Err(DecodeError::InvalidValue {
+                field: "frame length",
+                value: len,
+            });
+        }
+        Self::decode(kind, payload)
+    }
+}
+
+/// A frame whose kind has not been decoded yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Reads one frame, or `None` once the other side closes the connection
+pub async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Frame>> {
+    let mut len = [0u8; 8];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u64::from_be_bytes(len);
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid frame length {len}"),
+        ));
+    }
+    let mut kind = 0u8;
+    stream.read_exact(std::array::from_mut(&mut kind)).await?;
+    let mut payload = vec![0; len as usize - 1];
+    stream.read_exact(&mut payload).await?;
+    Ok(Some(Frame { kind, payload }))
+}
+
+/// Returns the length of the frame written
+pub async fn write_frame(
+    stream: &mut (impl AsyncWrite + Unpin),
+    message: &impl Message,
+) -> io::Result<usize> {
+    let frame = message
+        .to_frame()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+    Ok(frame.len())
+}
+
+/// Appends the count of a variable number of entries
+fn put_count(buffer: &mut Vec<u8>, field: &'static str, count: usize) -> Result<(), EncodeError> {
+    let count = u8::try_from(count).map_err(|_| EncodeError::TooMany { field, count })?;
+    buffer.push(count);
+    Ok(())
+}
+
+/// Checks the payload size of a fixed size message kind
+fn expect_len(kind: MessageKind, payload: &[u8], expected: usize) -> Result<(), DecodeError> {
+    if payload.len() != expected {
+        return Err(DecodeError::Length {
+            kind,
+            expected,
+            actual: payload.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Reads big-endian values off the front of a payload
+struct PayloadReader<'a>(&'a [u8]);
+
+impl<'a> PayloadReader<'a> {
+    fn new(payload: &'a [u8]) -> Self {
+        Self(payload)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let (head, tail) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or(DecodeError::Truncated)?;
+        self.0 = tail;
+        Ok(*head)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.0.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn rest(self) -> &'a [u8] {
+        self.0
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        self.take().map(|[byte]| byte)
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        self.take().map(u16::from_be_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.take().map(u32::from_be_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        self.take().map(u64::from_be_bytes)
+    }
+
+    fn f32(&mut self) -> Result<f32, DecodeError> {
+        self.take().map(f32::from_be_bytes)
+    }
+
+    fn f64(&mut self) -> Result<f64, DecodeError> {
+        self.take().map(f64::from_be_bytes)
+    }
+
+    fn f32s<const N: usize>(&mut self) -> Result<[f32; N], DecodeError> {
+        let mut values = [0.0; N];
+        for value in &mut values {
+            *value = self.f32()?;
+        }
+        Ok(values)
+    }
+
+    fn finish(self) -> Result<(), DecodeError> {
+        if !self.0.is_empty() {
+            return Err(DecodeError::TrailingBytes(self.0.len()));
+        }
+        Ok(())
+    }
+}
+
+fn put_f32s(buffer: &mut Vec<u8>, values: &[f32]) {
+    for value in values {
+        buffer.extend_from_slice(&value.to_be_bytes());
+    }
+}
@@ -0,0 +1,534 @@
+use std::mem::offset_of;
+
+use bevy::prelude::*;
+use image::{
+    ExtendedColorType, ImageEncoder as _, ImageFormat, ImageResult,
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, qoi::QoiEncoder},
+};
+use smallvec::SmallVec;
+
+use super::{
+    DecodeError, EncodeError, Hello, Message, MessageKind, PayloadReader, expect_len, put_count,
+    put_f32s, shm::ShmImage,
+};
+
+/// Messages sent from the sim to the HAL
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutgoingMessage {
+    Sensors(SensorMessage),
+    BotcamImage(ImageMessage),
+    ZedImage(ImageMessage),
+    /// At most 255 targets fit in a message, encoding more is an [`EncodeError`].
+    MlTarget(Stamp, SmallVec<[MLTargetData; 2]>, Vec2),
+    Hello(Hello),
+    ShmImage(ShmImage),
+    GroundTruth(GroundTruth),
+    Imu(Stamp, ImuINS, ImuPIMU),
+    Dvl(Stamp, Dvl),
+    Depth(Stamp, f32),
+}
+
+impl Message for OutgoingMessage {
+    fn kind(&self) -> MessageKind {
+        match self {
+            OutgoingMessage::Sensors(..) => MessageKind::Sensors,
+            OutgoingMessage::BotcamImage(..) => MessageKind::BotcamImage,
+            OutgoingMessage::ZedImage(..) => MessageKind::ZedImage,
+            OutgoingMessage::MlTarget(..) => MessageKind::MlTarget,
+            OutgoingMessage::Hello(..) => MessageKind::Hello,
+            OutgoingMessage::ShmImage(..) => MessageKind::ShmImage,
+            OutgoingMessage::GroundTruth(..) => MessageKind::GroundTruth,
+            OutgoingMessage::Imu(..) => MessageKind::Imu,
+            OutgoingMessage::Dvl(..) => MessageKind::Dvl,
+            OutgoingMessage::Depth(..) => MessageKind::Depth,
+        }
+    }
+
+    fn encode_payload(&self, buffer: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match self {
+            OutgoingMessage::Sensors(sensors) => {
+                buffer.extend_from_slice(&sensors.to_be_bytes());
+            }
+            OutgoingMessage::BotcamImage(image) | OutgoingMessage::ZedImage(image) => {
+                image.encode_payload(buffer);
+            }
+            OutgoingMessage::MlTarget(stamp, targets, size) => {
+                buffer.extend_from_slice(&stamp.to_be_bytes());
+                put_count(buffer, "ML targets", targets.len())?;
+                put_f32s(buffer, &size.to_array());
+                for target in targets {
+                    buffer.push(target.kind as u8);
+                    put_f32s(
+                        buffer,
+                        &[target.left, target.top, target.right, target.bottom],
+                    );
+                }
+            }
+            OutgoingMessage::Hello(hello) => hello.encode_payload(buffer)?,
+            OutgoingMessage::ShmImage(image) => image.encode_payload(buffer),
+            OutgoingMessage::GroundTruth(truth) => truth.encode_payload(buffer),
+            OutgoingMessage::Imu(stamp, ins, pimu) => {
+                buffer.extend_from_slice(&stamp.to_be_bytes());
+                buffer.extend_from_slice(&ins.to_be_bytes());
+                buffer.extend_from_slice(&pimu.to_be_bytes());
+            }
+            OutgoingMessage::Dvl(stamp, dvl) => {
+                buffer.extend_from_slice(&stamp.to_be_bytes());
+                buffer.extend_from_slice(&dvl.to_be_bytes());
+            }
+            OutgoingMessage::Depth(stamp, depth) => {
+                buffer.extend_from_slice(&stamp.to_be_bytes());
+                buffer.extend_from_slice(&depth.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_payload(kind: MessageKind, payload: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = PayloadReader::new(payload);
+        let message = match kind {
+            MessageKind::Sensors => {
+                expect_len(kind, payload, size_of::<SensorMessage>())?;
+                OutgoingMessage::Sensors(SensorMessage::read(&mut reader)?)
+            }
+            MessageKind::BotcamImage => {
+                OutgoingMessage::BotcamImage(ImageMessage::read(&mut reader)?)
+            }
+            MessageKind::ZedImage => OutgoingMessage::ZedImage(ImageMessage::read(&mut reader)?),
+            MessageKind::MlTarget => {
+                let stamp = Stamp::read(&mut reader)?;
+                let count = reader.u8()?;
+                let size = Vec2::from_array(reader.f32s()?);
+                let targets = (0..count)
+                    .map(|_| {
+                        let kind = MLTargetKind::try_from(reader.u8()?)?;
+                        let [left, top, right, bottom] = reader.f32s()?;
+                        Ok(MLTargetData {
+                            kind,
+                            left,
+                            top,
+                            right,
+                            bottom,
+                        })
+                    })
+                    .collect::<Result<_, DecodeError>>()?;
+                OutgoingMessage::MlTarget(stamp, targets, size)
+            }
+            MessageKind::Hello => OutgoingMessage::Hello(Hello::read(&mut reader)?),
+            MessageKind::ShmImage => OutgoingMessage::ShmImage(ShmImage::read(&mut reader)?),
+            MessageKind::GroundTruth => {
+                expect_len(kind, payload, GroundTruth::LEN)?;
+                OutgoingMessage::GroundTruth(GroundTruth::read(&mut reader)?)
+            }
+            MessageKind::Imu => {
+                let len = size_of::<Stamp>() + size_of::<ImuINS>() + size_of::<ImuPIMU>();
+                expect_len(kind, payload, len)?;
+                OutgoingMessage::Imu(
+                    Stamp::read(&mut reader)?,
+                    ImuINS::read(&mut reader)?,
+                    ImuPIMU::read(&mut reader)?,
+                )
+            }
+            MessageKind::Dvl => {
+                expect_len(kind, payload, size_of::<Stamp>() + size_of::<Dvl>())?;
+                OutgoingMessage::Dvl(Stamp::read(&mut reader)?, Dvl::read(&mut reader)?)
+            }
+            MessageKind::Depth => {
+                expect_len(kind, payload, size_of::<Stamp>() + size_of::<f32>())?;
+                OutgoingMessage::Depth(Stamp::read(&mut reader)?, reader.f32()?)
+            }
+            MessageKind::Motors
+            | MessageKind::BotcamOn
+            | MessageKind::ZedOn
+            | MessageKind::LocalizationEstimate
+            | MessageKind::BotcamEncoding
+            | MessageKind::ZedEncoding
+            | MessageKind::SensorAck
+            | MessageKind::ResetSub
+            | MessageKind::CoinFlip
+            | MessageKind::TeleportSub
+            | MessageKind::Pause
+            | MessageKind::Resume
+            | MessageKind::Step
+            | MessageKind::Seed => return Err(DecodeError::WrongDirection(kind)),
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+}
+
+fn flatten_array<const M: usize, const N: usize, const O: usize>(input: [[u8; N]; M]) -> [u8; O] {
+    assert_eq!(M * N, O);
+    let mut out = [0; O];
+    for i in 0..M {
+        out[(i * N)..((i + 1) * N)].copy_from_slice(&input[i]);
+    }
+    out
+}
+
+/// When a measurement was taken
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Stamp {
+    /// Seconds of sim time, or since the UNIX epoch if the sim is set to stamp with wall-clock time
+    pub time: f64,
+    /// Fixed ticks the sim had run when the measurement was taken
+    pub tick: u64,
+}
+
+impl Stamp {
+    pub fn to_be_bytes(&self) -> [u8; size_of::<Self>()] {
+        flatten_array([self.time.to_be_bytes(), self.tick.to_be_bytes()])
+    }
+
+    pub(super) fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            time: reader.f64()?,
+            tick: reader.u64()?,
+        })
+    }
+}
+
+/// Velocity along the body frame's axes, see [`crate::frames`]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Dvl {
+    pub velocity_a: f32,
+    pub velocity_b: f32,
+    pub velocity_c: f32,
+    /// Distance from the DVL straight down to the bottom
+    pub altitude: f32,
+    /// Whether each beam sees the bottom. The velocity is only valid with at least three, and is
+    /// zero otherwise. The altitude is valid with any.
+    pub beam_valid: [bool; 4],
+}
+
+impl Dvl {
+    pub fn bottom_lock(&self) -> bool {
+        self.beam_valid.iter().filter(|valid| **valid).count() >= 3
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; size_of::<Self>()] {
+        let mut bytes = [0; size_of::<Self>()];
+        let floats: [u8; 16] = flatten_array([
+            self.velocity_a.to_be_bytes(),
+            self.velocity_b.to_be_bytes(),
+            self.velocity_c.to_be_bytes(),
+            self.altitude.to_be_bytes(),
+        ]);
+        bytes[..16].copy_from_slice(&floats);
+        bytes[16..].copy_from_slice(&self.beam_valid.map(u8::from));
+        bytes
+    }
+
+    fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        let [velocity_a, velocity_b, velocity_c, altitude] = reader.f32s()?;
+        Ok(Self {
+            velocity_a,
+            velocity_b,
+            velocity_c,
+            altitude,
+            beam_valid: reader.take::<4>()?.map(|valid| valid != 0),
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct ImuINS {
+    /// Roll, pitch and yaw of the body frame in the world frame, see [`crate::frames`]
+    pub theta: [f32; 3],
+}
+
+impl ImuINS {
+    pub fn to_be_bytes(&self) -> [u8; size_of::<Self>()] {
+        flatten_array([
+            self.theta[0].to_be_bytes(),
+            self.theta[1].to_be_bytes(),
+            self.theta[2].to_be_bytes(),
+        ])
+    }
+
+    fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            theta: reader.f32s()?,
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct ImuPIMU {
+    /// Rotation vector over the last tick, in the body frame
+    pub dtheta: [f32; 3],
+    /// Specific force integrated over the last tick, in the body frame, so it includes gravity
+    pub dvel: [f32; 3],
+    pub dt: f32,
+}
+
+impl ImuPIMU {
+    pub fn to_be_bytes(&self) -> [u8; size_of::<Self>()] {
+        flatten_array([
+            self.dtheta[0].to_be_bytes(),
+            self.dtheta[1].to_be_bytes(),
+            self.dtheta[2].to_be_bytes(),
+            self.dvel[0].to_be_bytes(),
+            self.dvel[1].to_be_bytes(),
+            self.dvel[2].to_be_bytes(),
+            self.dt.to_be_bytes(),
+        ])
+    }
+
+    fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            dtheta: reader.f32s()?,
+            dvel: reader.f32s()?,
+            dt: reader.f32()?,
+        })
+    }
+}
+
+/// The latest sample of every sensor not sent as its own message kind, sent whenever one of them
+/// samples. The IMU's increments cover the time since its last sample, and are zero with a `dt` of
+/// zero when only other sensors sampled.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct SensorMessage {
+    pub stamp: Stamp,
+    pub depth: f32,
+    pub dvl: Dvl,
+    pub imu_ins: ImuINS,
+    pub imu_pimu: ImuPIMU,
+}
+
+impl SensorMessage {
+    pub fn to_be_bytes(&self) -> [u8; size_of::<Self>()] {
+        let mut bytes = [0; size_of::<Self>()];
+        macro_rules! copy_field {
+            ($field:ident) => {
+                bytes[offset_of!(Self, $field)
+                    ..offset_of!(Self, $field) + size_of_val(&self.$field)]
+                    .copy_from_slice(&self.$field.to_be_bytes());
+            };
+        }
+        copy_field!(stamp);
+        copy_field!(depth);
+        copy_field!(dvl);
+        copy_field!(imu_ins);
+        copy_field!(imu_pimu);
+        bytes
+    }
+
+    fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            stamp: Stamp::read(reader)?,
+            depth: reader.f32()?,
+            dvl: Dvl::read(reader)?,
+            imu_ins: ImuINS::read(reader)?,
+            imu_pimu: ImuPIMU::read(reader)?,
+        })
+    }
+}
+
+/// The sub's exact state, in the same frame as [`crate::protocol::IncomingMessage::TeleportSub`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroundTruth {
+    pub stamp: Stamp,
+    pub rotation: Mat3,
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub angular_velocity: Vec3,
+}
+
+impl GroundTruth {
+    const LEN: usize = size_of::<Stamp>() + size_of::<[f32; 18]>();
+
+    fn encode_payload(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.stamp.to_be_bytes());
+        put_f32s(buffer, &self.rotation.to_cols_array());
+        put_f32s(buffer, &self.position.to_array());
+        put_f32s(buffer, &self.velocity.to_array());
+        put_f32s(buffer, &self.angular_velocity.to_array());
+    }
+
+    fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            stamp: Stamp::read(reader)?,
+            rotation: Mat3::from_cols_array(&reader.f32s()?),
+            position: Vec3::from_array(reader.f32s()?),
+            velocity: Vec3::from_array(reader.f32s()?),
+            angular_velocity: Vec3::from_array(reader.f32s()?),
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Reflect)]
+#[reflect(Debug, Clone, PartialEq)]
+#[repr(u8)]
+pub enum MLTargetKind {
+    GateRed = 0,
+    GateBlue = 1,
+    #[default]
+    None = 255,
+}
+
+impl TryFrom<u8> for MLTargetKind {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, DecodeError> {
+        match value {
+            0 => Ok(Self::GateRed),
+            1 => Ok(Self::GateBlue),
+            255 => Ok(Self::None),
+            _ => Err(DecodeError::InvalidValue {
+                field: "ML target kind",
+                value: value as u64,
+            }),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MLTargetData {
+    pub kind: MLTargetKind,
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+/// How camera frames are compressed before being sent to the HAL
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Reflect)]
+#[reflect(Debug, Clone, PartialEq, Default)]
+#[repr(u8)]
+pub enum ImageEncoding {
+    /// Uncompressed RGBA8
+    #[default]
+    Raw = 0,
+    Qoi = 1,
+    Png = 2,
+    /// RGB8, the alpha channel is dropped
+    Jpeg = 3,
+}
+
+impl TryFrom<u8> for ImageEncoding {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, DecodeError> {
+        match value {
+            0 => Ok(Self::Raw),
+            1 => Ok(Self::Qoi),
+            2 => Ok(Self::Png),
+            3 => Ok(Self::Jpeg),
+            _ => Err(DecodeError::InvalidValue {
+                field: "image encoding",
+                value: value as u64,
+            }),
+        }
+    }
+}
+
+const JPEG_QUALITY: u8 = 80;
+
+/// A camera frame, possibly compressed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageMessage {
+    /// When the frame was rendered
+    pub stamp: Stamp,
+    pub width: u32,
+    pub height: u32,
+    pub encoding: ImageEncoding,
+    pub data: Vec<u8>,
+}
+
+impl ImageMessage {
+    /// An uncompressed frame of RGBA8 pixels
+    pub fn raw(stamp: Stamp, width: u32, height: u32, rgba: Vec<u8>) -> Self {
+        Self {
+            stamp,
+            width,
+            height,
+            encoding: ImageEncoding::Raw,
+            data: rgba,
+        }
+    }
+
+    /// Re-encodes the frame's pixels with the given encoding
+    pub fn compress(&mut self, encoding: ImageEncoding) -> ImageResult<()> {
+        if encoding == self.encoding {
+            return Ok(());
+        }
+        let rgba = self.to_rgba8()?;
+        let mut data = Vec::new();
+        match encoding {
+            ImageEncoding::Raw => data = rgba,
+            ImageEncoding::Qoi => QoiEncoder::new(&mut data).write_image(
+                &rgba,
+                self.width,
+                self.height,
+                ExtendedColorType::Rgba8,
+            )?,
+            ImageEncoding::Png => PngEncoder::new(&mut data).write_image(
+                &rgba,
+                self.width,
+                self.height,
+                ExtendedColorType::Rgba8,
+            )?,
+            ImageEncoding::Jpeg => {
+                let rgb: Vec<u8> = rgba
+                    .chunks_exact(4)
+                    .flat_map(|pixel| &pixel[..3])
+                    .copied()
+                    .collect();
+                JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY).write_image(
+                    &rgb,
+                    self.width,
+                    self.height,
+                    ExtendedColorType::Rgb8,
+                )?
+            }
+        }
+        self.encoding = encoding;
+        self.data = data;
+        Ok(())
+    }
+
+    /// Decompresses the frame into RGBA8 pixels
+    pub fn to_rgba8(&self) -> ImageResult<Vec<u8>> {
+        let format = match self.encoding {
+            ImageEncoding::Raw => return Ok(self.data.clone()),
+            ImageEncoding::Qoi => ImageFormat::Qoi,
+            ImageEncoding::Png => ImageFormat::Png,
+            ImageEncoding::Jpeg => ImageFormat::Jpeg,
+        };
+        Ok(image::load_from_memory_with_format(&self.data, format)?
+            .into_rgba8()
+            .into_raw())
+    }
+
+    fn encode_payload(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.stamp.to_be_bytes());
+        buffer.extend_from_slice(&self.width.to_be_bytes());
+        buffer.extend_from_slice(&self.height.to_be_bytes());
+        buffer.push(self.encoding as u8);
+        buffer.extend_from_slice(&(self.data.len() as u64).to_be_bytes());
+        buffer.extend_from_slice(&self.data);
+    }
+
+    fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        let stamp = Stamp::read(reader)?;
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let encoding = ImageEncoding::try_from(reader.u8()?)?;
+        let len = reader.u64()?;
+        let data = reader.bytes(len as usize)?.to_vec();
+        Ok(Self {
+            stamp,
+            width,
+            height,
+            encoding,
+            data,
+        })
+    }
+}
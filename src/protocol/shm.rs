@@ -0,0 +1,232 @@
+//! Camera frames passed through shared memory, for a HAL on the same machine as the sim.
+//!
+//! Each camera writes its frames into a ring of fixed size slots in a file, usually under
+//! `/dev/shm`, and only sends a small [`ShmImage`] frame saying which slot to read.
+//!
+//! The file starts with a [`HEADER_LEN`] byte header: the magic bytes `SUBSHM01`, then the
+//! slot count as a `u32`, 4 reserved bytes and the slot size as a `u64`. Slot `n` starts at
+//! `HEADER_LEN + n * (SLOT_HEADER_LEN + slot size)`. Its header holds the `u64` sequence number
+//! of the frame in it, which is 0 while the frame is being written, and the frame data follows.
+//! A reader copies the data out, then checks the sequence number still matches the notification,
+//! as otherwise the sim has started overwriting the slot.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    ptr,
+    sync::atomic::{AtomicU64, Ordering, fence},
+};
+
+use memmap2::{Mmap, MmapMut};
+
+use super::{DecodeError, ImageEncoding, MessageKind, PayloadReader, Stamp};
+
+/// Where the sim and HAL exchange notifications unless configured otherwise
+pub const DEFAULT_SOCKET: &str = "/tmp/subsim-hal.sock";
+/// Start of the ring file paths unless configured otherwise, see [`ring_path`]
+pub const DEFAULT_PREFIX: &str = "/dev/shm/subsim";
+
+pub const MAGIC: [u8; 8] = *b"SUBSHM01";
+pub const HEADER_LEN: usize = 64;
+pub const SLOT_HEADER_LEN: usize = 64;
+
+/// Notification that a camera frame is waiting in its ring
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShmImage {
+    /// The kind of message the frame would have been sent as over TCP
+    pub camera: MessageKind,
+    pub stamp: Stamp,
+    pub width: u32,
+    pub height: u32,
+    pub encoding: ImageEncoding,
+    pub slot: u32,
+    pub sequence: u64,
+    /// Bytes of frame data in the slot
+    pub len: u64,
+}
+
+impl ShmImage {
+    pub(super) fn encode_payload(&self, buffer: &mut Vec<u8>) {
+        buffer.push(self.camera as u8);
+        buffer.extend_from_slice(&self.stamp.to_be_bytes());
+        buffer.extend_from_slice(&self.width.to_be_bytes());
+        buffer.extend_from_slice(&self.height.to_be_bytes());
+        buffer.push(self.encoding as u8);
+        buffer.extend_from_slice(&self.slot.to_be_bytes());
+        buffer.extend_from_slice(&self.sequence.to_be_bytes());
+        buffer.extend_from_slice(&self.len.to_be_bytes());
+    }
+
+    pub(super) fn read(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            camera: MessageKind::try_from(reader.u8()?)?,
+            stamp: Stamp::read(reader)?,
+            width: reader.u32()?,
+            height: reader.u32()?,
+            encoding: ImageEncoding::try_from(reader.u8()?)?,
+            slot: reader.u32()?,
+            sequence: reader.u64()?,
+            len: reader.u64()?,
+        })
+    }
+}
+
+/// Cameras with a ring, each with its own connection to the shared memory socket
+pub const CAMERAS: [MessageKind; 2] = [MessageKind::BotcamImage, MessageKind::ZedImage];
+
+/// Where a camera's ring is, e.g. `/dev/shm/subsim-zed` for the ZED with the default prefix
+pub fn ring_path(prefix: &Path, camera: MessageKind) -> Option<PathBuf> {
+    let camera = match camera {
+        MessageKind::BotcamImage => "botcam",
+        MessageKind::ZedImage => "zed",
+        _ => return None,
+    };
+    Some(format!("{}-{camera}", prefix.display()).into())
+}
+
+/// Where slot `slot` starts in a ring with slots of `slot_size` bytes
+fn slot_offset(slot: u32, slot_size: usize) -> usize {
+    HEADER_LEN + slot as usize * (SLOT_HEADER_LEN + slot_size)
+}
+
+/// The sequence number at the start of a slot header
+///
+/// # Safety
+/// `offset` must be a slot offset within the mapping starting at `base`, which is page aligned,
+/// and the mapping must outlive `'a`.
+unsafe fn sequence_at<'a>(base: *const u8, offset: usize) -> &'a AtomicU64 {
+    unsafe { AtomicU64::from_ptr(base.add(offset) as *mut u64) }
+}
+
+/// The sim's side of a camera's ring
+#[derive(Debug)]
+pub struct ShmRingWriter {
+    map: MmapMut,
+    slots: u32,
+    slot_size: usize,
+    next_slot: u32,
+    sequence: u64,
+}
+
+impl ShmRingWriter {
+    /// Creates the ring file, replacing any left over from an earlier run
+    pub fn create(path: &Path, slots: u32, slot_size: usize) -> io::Result<Self> {
+        if slots == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ring needs at least one slot",
+            ));
+        }
+        // Keeps the frame data of every slot aligned
+        let slot_size = slot_size.next_multiple_of(SLOT_HEADER_LEN);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(slot_offset(slots, slot_size) as u64)?;
+        // SAFETY: the file is only ever written through this mapping
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        map[..MAGIC.len()].copy_from_slice(&MAGIC);
+        map[8..12].copy_from_slice(&slots.to_be_bytes());
+        map[16..24].copy_from_slice(&(slot_size as u64).to_be_bytes());
+        Ok(Self {
+            map,
+            slots,
+            slot_size,
+            next_slot: 0,
+            sequence: 0,
+        })
+    }
+
+    /// Copies a frame into the next slot, returning the slot and the frame's sequence number
+    pub fn write(&mut self, data: &[u8]) -> io::Result<(u32, u64)> {
+        if data.len() > self.slot_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} byte frame does not fit in {} byte slot",
+                    data.len(),
+                    self.slot_size
+                ),
+            ));
+        }
+        let slot = self.next_slot;
+        self.next_slot = (slot + 1) % self.slots;
+        self.sequence += 1;
+        let offset = slot_offset(slot, self.slot_size);
+        let base = self.map.as_mut_ptr();
+        // SAFETY: `offset` is a slot offset within the mapping
+        let sequence = unsafe { sequence_at(base, offset) };
+        sequence.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        // SAFETY: the frame fits in the slot, and is written through the same pointer as its
+        // sequence number so the two never alias a reference
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                base.add(offset + SLOT_HEADER_LEN),
+                data.len(),
+            );
+        }
+        sequence.store(self.sequence.to_be(), Ordering::Release);
+        Ok((slot, self.sequence))
+    }
+}
+
+/// The HAL's side of a camera's ring
+#[derive(Debug)]
+pub struct ShmRingReader {
+    map: Mmap,
+    slots: u32,
+    slot_size: usize,
+}
+
+impl ShmRingReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the sim only writes frame data, which is checked against its sequence number
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let header = map
+            .get(..HEADER_LEN)
+            .ok_or_else(|| invalid("ring has no header"))?;
+        if header[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a camera ring"));
+        }
+        let slots = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let slot_size = u64::from_be_bytes(header[16..24].try_into().unwrap()) as usize;
+        if map.len() < slot_offset(slots, slot_size) {
+            return Err(invalid("ring is shorter than its slots"));
+        }
+        Ok(Self {
+            map,
+            slots,
+            slot_size,
+        })
+    }
+
+    /// Copies out the frame a notification points to, or `None` if it was overwritten
+    pub fn read(&self, image: &ShmImage) -> Option<Vec<u8>> {
+        if image.slot >= self.slots || image.len as usize > self.slot_size {
+            return None;
+        }
+        let offset = slot_offset(image.slot, self.slot_size);
+        // SAFETY: `offset` is a slot offset within the mapping
+        let sequence = unsafe { sequence_at(self.map.as_ptr(), offset) };
+        if u64::from_be(sequence.load(Ordering::Acquire)) != image.sequence {
+            return None;
+        }
+        let mut data = vec![0; image.len as usize];
+        // SAFETY: in bounds. The sim may be overwriting the slot, which the sequence number
+        // check below catches
+        unsafe {
+            let start = self.map.as_ptr().add(offset + SLOT_HEADER_LEN);
+            ptr::copy_nonoverlapping(start, data.as_mut_ptr(), data.len());
+        }
+        fence(Ordering::Acquire);
+        (u64::from_be(sequence.load(Ordering::Relaxed)) == image.sequence).then_some(data)
+    }
+}
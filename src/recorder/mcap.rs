@@ -0,0 +1,206 @@
+//! Just enough of the [MCAP](https://mcap.dev/spec) format to write a log Foxglove can open.
+//!
+//! Records are written unchunked and without a summary section, which readers fall back to
+//! scanning the whole file for. The data end record and footer are only written by
+//! [`McapWriter::finish`] when the sim exits, so a log cut short by a crash has to be recovered
+//! with `mcap recover`. Flushing regularly with [`McapWriter::flush`] keeps how much it loses
+//! short.
+
+use std::io::{self, Write};
+
+const MAGIC: &[u8] = b"\x89MCAP0\r\n";
+
+const OP_HEADER: u8 = 0x01;
+const OP_FOOTER: u8 = 0x02;
+const OP_SCHEMA: u8 = 0x03;
+const OP_CHANNEL: u8 = 0x04;
+const OP_MESSAGE: u8 = 0x05;
+const OP_DATA_END: u8 = 0x0F;
+
+pub struct McapWriter<W: Write> {
+    out: W,
+    next_schema: u16,
+    next_channel: u16,
+    /// Per channel, indexed by channel id
+    sequences: Vec<u32>,
+}
+
+impl<W: Write> McapWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        let mut writer = Self {
+            out,
+            // Schema id 0 means a channel has no schema
+            next_schema: 1,
+            next_channel: 0,
+            sequences: Vec::new(),
+        };
+        let mut header = Vec::new();
+        put_str(&mut header, "");
+        put_str(&mut header, env!("CARGO_PKG_NAME"));
+        writer.record(OP_HEADER, &header)?;
+        Ok(writer)
+    }
+
+    pub fn add_schema(&mut self, name: &str, encoding: &str, data: &[u8]) -> io::Result<u16> {
+        let id = self.next_schema;
+        self.next_schema += 1;
+        let mut schema = Vec::new();
+        schema.extend_from_slice(&id.to_le_bytes());
+        put_str(&mut schema, name);
+        put_str(&mut schema, encoding);
+        put_bytes(&mut schema, data);
+        self.record(OP_SCHEMA, &schema)?;
+        Ok(id)
+    }
+
+    pub fn add_channel(
+        &mut self,
+        schema: u16,
+        topic: &str,
+        message_encoding: &str,
+    ) -> io::Result<u16> {
+        let id = self.next_channel;
+        self.next_channel += 1;
+        self.sequences.push(0);
+        let mut channel = Vec::new();
+        channel.extend_from_slice(&id.to_le_bytes());
+        channel.extend_from_slice(&schema.to_le_bytes());
+        put_str(&mut channel, topic);
+        put_str(&mut channel, message_encoding);
+        // Empty metadata map
+        channel.extend_from_slice(&0u32.to_le_bytes());
+        self.record(OP_CHANNEL, &channel)?;
+        Ok(id)
+    }
+
+    /// Writes a message logged at `time` nanoseconds
+    pub fn write_message(&mut self, channel: u16, time: u64, data: &[u8]) -> io::Result<()> {
+        let sequence = &mut self.sequences[channel as usize];
+        let mut message = Vec::with_capacity(22 + data.len());
+        message.extend_from_slice(&channel.to_le_bytes());
+        message.extend_from_slice(&sequence.to_le_bytes());
+        message.extend_from_slice(&time.to_le_bytes());
+        message.extend_from_slice(&time.to_le_bytes());
+        message.extend_from_slice(data);
+        *sequence = sequence.wrapping_add(1);
+        self.record(OP_MESSAGE, &message)
+    }
+
+    /// Flushes the records written so far through to the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Ends the file, which is not a valid MCAP until this is called
+    pub fn finish(mut self) -> io::Result<W> {
+        // A zero CRC means it was not calculated
+        self.record(OP_DATA_END, &0u32.to_le_bytes())?;
+        let mut footer = Vec::new();
+        // No summary section
+        footer.extend_from_slice(&0u64.to_le_bytes());
+        footer.extend_from_slice(&0u64.to_le_bytes());
+        footer.extend_from_slice(&0u32.to_le_bytes());
+        self.record(OP_FOOTER, &footer)?;
+        self.out.write_all(MAGIC)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn record(&mut self, op: u8, content: &[u8]) -> io::Result<()> {
+        self.out.write_all(&[op])?;
+        self.out.write_all(&(content.len() as u64).to_le_bytes())?;
+        self.out.write_all(content)
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_bytes(buf, s.as_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits a finished file into its records, checking the magic on both ends
+    fn records(file: &[u8]) -> Vec<(u8, &[u8])> {
+        let body = file
+            .strip_prefix(MAGIC)
+            .and_then(|file| file.strip_suffix(MAGIC))
+            .expect("file should start and end with the magic");
+        let mut rest = body;
+        let mut records = Vec::new();
+        while let Some((&op, tail)) = rest.split_first() {
+            let (len, tail) = tail.split_first_chunk::<8>().expect("record length");
+            let (content, tail) = tail.split_at(u64::from_le_bytes(*len) as usize);
+            records.push((op, content));
+            rest = tail;
+        }
+        records
+    }
+
+    #[test]
+    fn messages_read_back() {
+        let mut mcap = McapWriter::new(Vec::new()).unwrap();
+        let schema = mcap.add_schema("test.Schema", "jsonschema", b"{}").unwrap();
+        let first = mcap.add_channel(schema, "/first", "json").unwrap();
+        let second = mcap.add_channel(schema, "/second", "json").unwrap();
+        mcap.write_message(first, 10, b"a").unwrap();
+        mcap.write_message(second, 20, b"bc").unwrap();
+        mcap.write_message(first, 30, b"def").unwrap();
+        let file = mcap.finish().unwrap();
+
+        let records = records(&file);
+        let ops: Vec<_> = records.iter().map(|&(op, _)| op).collect();
+        assert_eq!(
+            ops,
+            [
+                OP_HEADER,
+                OP_SCHEMA,
+                OP_CHANNEL,
+                OP_CHANNEL,
+                OP_MESSAGE,
+                OP_MESSAGE,
+                OP_MESSAGE,
+                OP_DATA_END,
+                OP_FOOTER,
+            ]
+        );
+
+        let (_, schema_record) = records[1];
+        assert_eq!(schema_record[..2], schema.to_le_bytes());
+        let (_, channel_record) = records[3];
+        assert_eq!(channel_record[..2], second.to_le_bytes());
+        assert_eq!(channel_record[2..4], schema.to_le_bytes());
+        assert_eq!(channel_record[4..8], 7u32.to_le_bytes());
+        assert_eq!(&channel_record[8..15], b"/second");
+
+        let messages: Vec<_> = records[4..7]
+            .iter()
+            .map(|(_, message)| {
+                let channel = u16::from_le_bytes(message[..2].try_into().unwrap());
+                let sequence = u32::from_le_bytes(message[2..6].try_into().unwrap());
+                let log_time = u64::from_le_bytes(message[6..14].try_into().unwrap());
+                let publish_time = u64::from_le_bytes(message[14..22].try_into().unwrap());
+                assert_eq!(log_time, publish_time);
+                (channel, sequence, log_time, &message[22..])
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (first, 0, 10, &b"a"[..]),
+                (second, 0, 20, b"bc"),
+                (first, 1, 30, b"def"),
+            ]
+        );
+
+        let (_, footer) = records[8];
+        assert_eq!(footer, [0; 20]);
+    }
+}
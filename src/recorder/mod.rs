@@ -0,0 +1,807 @@
+//! Records everything the sim sends to and gets from the HAL, alongside ground truth,
+//! to an MCAP file for review in Foxglove.
+
+mod mcap;
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use avian3d::prelude::{Position, Rotation};
+use bevy::{platform::collections::HashMap, prelude::*};
+use futures_lite::future;
+use mcap::McapWriter;
+use serde_json::{Value, json};
+use subsimgpt2::{
+    config::Config,
+    frames::Frames,
+    protocol::{
+        Dvl, ImageEncoding, ImageMessage, ImuPIMU, IncomingMessage, MessageKind, OutgoingMessage,
+        SensorMessage, Stamp,
+    },
+};
+
+use crate::{
+    hal::{DropPolicy, Monitor, Outgoing, Queue, QueueConfig, Queued, SimClock, TimestampClock},
+    sim::sub::{
+        SubControls,
+        thruster::{ThrusterForce, ThrusterOf, ThrusterState, ThrusterTarget},
+    },
+};
+
+/// Writes an MCAP file to the `mcap` config path, if it is set.
+///
+/// Must be added after the [`HalPlugin`](crate::hal::HalPlugin).
+#[derive(Debug, Default, Clone)]
+pub struct RecorderPlugin;
+
+impl Plugin for RecorderPlugin {
+    fn build(&self, app: &mut App) {
+        let config = app
+            .world()
+            .get_resource::<Config>()
+            .cloned()
+            .unwrap_or_default();
+        let Some(path) = config
+            .get::<PathBuf>("mcap")
+            .expect("MCAP path should be valid")
+        else {
+            return;
+        };
+        let outgoing = app
+            .world()
+            .get_resource::<Outgoing>()
+            .expect("HalPlugin should be added before RecorderPlugin")
+            .monitor();
+        let recorder = McapRecorder::start(&path, outgoing).expect("MCAP file should be created");
+        info!("Recording to {}", path.display());
+        app.insert_resource(recorder)
+            .add_systems(
+                FixedLast,
+                (record_outgoing, record_thrusters, record_ground_truth),
+            )
+            // Also picks up camera frames and ML targets, which are sent outside the fixed loop
+            .add_systems(Last, (record_outgoing, record_localization));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Topic {
+    Sensors,
+    Imu,
+    Dvl,
+    Depth,
+    MlTargets,
+    BotcamImage,
+    ZedImage,
+    BotcamCompressed,
+    ZedCompressed,
+    Thrusters,
+    GroundTruth,
+    Localization,
+}
+
+impl Topic {
+    const ALL: [Self; 12] = [
+        Self::Sensors,
+        Self::Imu,
+        Self::Dvl,
+        Self::Depth,
+        Self::MlTargets,
+        Self::BotcamImage,
+        Self::ZedImage,
+        Self::BotcamCompressed,
+        Self::ZedCompressed,
+        Self::Thrusters,
+        Self::GroundTruth,
+        Self::Localization,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Topic::Sensors => "/sensors",
+            Topic::Imu => "/imu",
+            Topic::Dvl => "/dvl",
+            Topic::Depth => "/depth",
+            Topic::MlTargets => "/ml_targets",
+            Topic::BotcamImage => "/botcam/image",
+            Topic::ZedImage => "/zed/image",
+            Topic::BotcamCompressed => "/botcam/image/compressed",
+            Topic::ZedCompressed => "/zed/image/compressed",
+            Topic::Thrusters => "/thrusters",
+            Topic::GroundTruth => "/ground_truth/pose",
+            Topic::Localization => "/localization/estimate",
+        }
+    }
+
+    /// Camera frames are recorded whole, so compressed ones go on their own topic
+    fn image(camera: MessageKind, encoding: ImageEncoding) -> Self {
+        let compressed = matches!(encoding, ImageEncoding::Png | ImageEncoding::Jpeg);
+        match (camera, compressed) {
+            (MessageKind::BotcamImage, false) => Topic::BotcamImage,
+            (MessageKind::BotcamImage, true) => Topic::BotcamCompressed,
+            (_, false) => Topic::ZedImage,
+            (_, true) => Topic::ZedCompressed,
+        }
+    }
+
+    /// Name and definition of the schema of the messages on this topic
+    fn schema(self) -> (&'static str, Schema) {
+        let (name, schema) = match self {
+            Topic::BotcamImage | Topic::ZedImage => {
+                return ("sensor_msgs/Image", Schema::Ros1(ROS1_IMAGE));
+            }
+            Topic::BotcamCompressed | Topic::ZedCompressed => {
+                return (
+                    "sensor_msgs/CompressedImage",
+                    Schema::Ros1(ROS1_COMPRESSED_IMAGE),
+                );
+            }
+            Topic::Sensors => (
+                "subsim.Sensors",
+                object(json!({
+                    "tick": { "type": "integer" },
+                    "depth": number(),
+                    "dvl": dvl_schema(),
+                    "imu_ins": imu_ins_schema(),
+                    "imu_pimu": imu_pimu_schema(),
+                })),
+            ),
+            Topic::Imu => (
+                "subsim.Imu",
+                object(json!({
+                    "tick": { "type": "integer" },
+                    "imu_ins": imu_ins_schema(),
+                    "imu_pimu": imu_pimu_schema(),
+                })),
+            ),
+            Topic::Dvl => (
+                "subsim.Dvl",
+                object(json!({
+                    "tick": { "type": "integer" },
+                    "dvl": dvl_schema(),
+                })),
+            ),
+            Topic::Depth => (
+                "subsim.Depth",
+                object(json!({
+                    "tick": { "type": "integer" },
+                    "depth": number(),
+                })),
+            ),
+            Topic::MlTargets => (
+                "subsim.MlTargets",
+                object(json!({
+                    "tick": { "type": "integer" },
+                    "targets": {
+                        "type": "array",
+                        "items": object(json!({
+                            "kind": { "type": "string" },
+                            "left": number(),
+                            "top": number(),
+                            "right": number(),
+                            "bottom": number(),
+                        })),
+                    },
+                    "width": number(),
+                    "height": number(),
+                })),
+            ),
+            Topic::Thrusters => (
+                "subsim.Thrusters",
+                object(json!({
+                    "thrusters": {
+                        "type": "array",
+                        "items": object(json!({
+                            "id": { "type": "integer" },
+                            "target": number(),
+                            "output": number(),
+                            "force": number(),
+                        })),
+                    },
+                })),
+            ),
+            Topic::GroundTruth => ("foxglove.PoseInFrame", object(pose_properties())),
+            Topic::Localization => {
+                let mut properties = pose_properties();
+                properties["velocity"] = vector3_schema();
+                ("subsim.LocalizationEstimate", object(properties))
+            }
+        };
+        (name, Schema::Json(schema))
+    }
+}
+
+enum Schema {
+    Json(Value),
+    /// A ROS 1 message definition, for messages in ROS 1's binary serialization
+    Ros1(&'static str),
+}
+
+impl Schema {
+    /// Schema and message encodings, as MCAP names them
+    fn encodings(&self) -> (&'static str, &'static str) {
+        match self {
+            Schema::Json(_) => ("jsonschema", "json"),
+            Schema::Ros1(_) => ("ros1msg", "ros1"),
+        }
+    }
+
+    fn data(&self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Schema::Json(schema) => serde_json::to_vec(schema)?,
+            Schema::Ros1(definition) => definition.as_bytes().to_vec(),
+        })
+    }
+}
+
+/// The definition `sensor_msgs` images depend on, appended to theirs
+macro_rules! ros1_header {
+    () => {
+        "\
+================================================================================
+MSG: std_msgs/Header
+uint32 seq
+time stamp
+string frame_id
+"
+    };
+}
+
+const ROS1_IMAGE: &str = concat!(
+    "\
+std_msgs/Header header
+uint32 height
+uint32 width
+string encoding
+uint8 is_bigendian
+uint32 step
+uint8[] data
+",
+    ros1_header!()
+);
+
+const ROS1_COMPRESSED_IMAGE: &str = concat!(
+    "\
+std_msgs/Header header
+string format
+uint8[] data
+",
+    ros1_header!()
+);
+
+fn number() -> Value {
+    json!({ "type": "number" })
+}
+
+fn numbers() -> Value {
+    json!({ "type": "array", "items": number() })
+}
+
+fn object(properties: Value) -> Value {
+    json!({ "type": "object", "properties": properties })
+}
+
+fn dvl_schema() -> Value {
+    object(json!({
+        "velocity_a": number(),
+        "velocity_b": number(),
+        "velocity_c": number(),
+        "altitude": number(),
+        "beam_valid": { "type": "array", "items": { "type": "boolean" } },
+    }))
+}
+
+fn imu_ins_schema() -> Value {
+    object(json!({ "theta": numbers() }))
+}
+
+fn imu_pimu_schema() -> Value {
+    object(json!({
+        "dtheta": numbers(),
+        "dvel": numbers(),
+        "dt": number(),
+    }))
+}
+
+fn timestamp_schema() -> Value {
+    object(json!({
+        "sec": { "type": "integer" },
+        "nsec": { "type": "integer" },
+    }))
+}
+
+fn vector3_schema() -> Value {
+    object(json!({ "x": number(), "y": number(), "z": number() }))
+}
+
+fn pose_properties() -> Value {
+    json!({
+        "timestamp": timestamp_schema(),
+        "frame_id": { "type": "string" },
+        "pose": object(json!({
+            "position": vector3_schema(),
+            "orientation": object(json!({
+                "x": number(),
+                "y": number(),
+                "z": number(),
+                "w": number(),
+            })),
+        })),
+    })
+}
+
+fn timestamp(time: Duration) -> Value {
+    json!({ "sec": time.as_secs(), "nsec": time.subsec_nanos() })
+}
+
+fn vector3(v: Vec3) -> Value {
+    json!({ "x": v.x, "y": v.y, "z": v.z })
+}
+
+/// Poses are recorded in the frame the HAL uses, so ground truth and estimates line up
+fn pose(time: Duration, position: Vec3, orientation: Quat) -> Value {
+    json!({
+        "timestamp": timestamp(time),
+        "frame_id": "hal",
+        "pose": {
+            "position": vector3(position),
+            "orientation": {
+                "x": orientation.x,
+                "y": orientation.y,
+                "z": orientation.z,
+                "w": orientation.w,
+            },
+        },
+    })
+}
+
+fn sensors_json(sensors: &SensorMessage) -> Value {
+    json!({
+        "tick": sensors.stamp.tick,
+        "depth": sensors.depth,
+        "dvl": dvl_json(&sensors.dvl),
+        "imu_ins": { "theta": sensors.imu_ins.theta },
+        "imu_pimu": imu_pimu_json(&sensors.imu_pimu),
+    })
+}
+
+fn dvl_json(dvl: &Dvl) -> Value {
+    json!({
+        "velocity_a": dvl.velocity_a,
+        "velocity_b": dvl.velocity_b,
+        "velocity_c": dvl.velocity_c,
+        "altitude": dvl.altitude,
+        "beam_valid": dvl.beam_valid,
+    })
+}
+
+fn imu_pimu_json(pimu: &ImuPIMU) -> Value {
+    json!({
+        "dtheta": pimu.dtheta,
+        "dvel": pimu.dvel,
+        "dt": pimu.dt,
+    })
+}
+
+/// Serializes a camera frame as a ROS 1 `sensor_msgs/Image`, or `sensor_msgs/CompressedImage`
+/// on the compressed topics. Foxglove can only show PNG and JPEG frames, so QOI ones are
+/// recorded raw.
+fn image_ros1(
+    topic: Topic,
+    time: Duration,
+    mut image: ImageMessage,
+    encoding: ImageEncoding,
+) -> Result<Vec<u8>> {
+    let frame_id = match topic {
+        Topic::BotcamImage | Topic::BotcamCompressed => "botcam",
+        _ => "zed",
+    };
+    let mut message = Vec::new();
+    // std_msgs/Header, without a sequence number since MCAP keeps its own
+    message.extend_from_slice(&0u32.to_le_bytes());
+    message.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+    message.extend_from_slice(&time.subsec_nanos().to_le_bytes());
+    put_ros1_bytes(&mut message, frame_id.as_bytes());
+    match topic {
+        Topic::BotcamCompressed | Topic::ZedCompressed => {
+            image.compress(encoding)?;
+            let format = match encoding {
+                ImageEncoding::Jpeg => "jpeg",
+                _ => "png",
+            };
+            put_ros1_bytes(&mut message, format.as_bytes());
+        }
+        _ => {
+            image.compress(ImageEncoding::Raw)?;
+            message.extend_from_slice(&image.height.to_le_bytes());
+            message.extend_from_slice(&image.width.to_le_bytes());
+            put_ros1_bytes(&mut message, b"rgba8");
+            // Not big-endian
+            message.push(0);
+            message.extend_from_slice(&(image.width * 4).to_le_bytes());
+        }
+    }
+    put_ros1_bytes(&mut message, &image.data);
+    Ok(message)
+}
+
+/// Strings and `uint8[]` are both a length, then the bytes
+fn put_ros1_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+enum Payload {
+    Json(Value),
+    /// Compressed on the writer thread, if it is not recorded raw
+    Image(ImageMessage, ImageEncoding),
+}
+
+struct Record {
+    topic: Topic,
+    /// Sim time
+    time: Duration,
+    payload: Payload,
+}
+
+/// Records waiting for the writer. Camera frames are big and slow to encode, so only the newest
+/// are kept when the writer falls behind.
+#[derive(Clone)]
+struct Records {
+    images: Queue<Record>,
+    others: Queue<Record>,
+}
+
+impl Records {
+    const IMAGES: QueueConfig = QueueConfig {
+        policy: DropPolicy::Latest,
+        capacity: 4,
+    };
+    const OTHERS: QueueConfig = QueueConfig {
+        policy: DropPolicy::Fifo,
+        capacity: 4096,
+    };
+
+    fn new() -> Self {
+        Self {
+            images: Queue::new(Self::IMAGES),
+            others: Queue::new(Self::OTHERS),
+        }
+    }
+
+    /// Returns how many records were dropped to make room
+    fn push(&self, record: Record) -> usize {
+        match record.payload {
+            Payload::Image(..) => self.images.push(record),
+            Payload::Json(_) => self.others.push(record),
+        }
+    }
+
+    fn try_recv(&self) -> Option<Record> {
+        self.others.try_recv().or_else(|| self.images.try_recv())
+    }
+
+    /// Waits up to `timeout` for the next record, or `None` if none came or the queues are closed
+    fn recv_timeout(&self, timeout: Duration) -> Option<Record> {
+        future::block_on(future::or(
+            future::or(self.others.recv(), self.images.recv()),
+            async {
+                Timer::after(timeout).await;
+                None
+            },
+        ))
+    }
+
+    fn close(&self) {
+        self.images.close();
+        self.others.close();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.others.is_closed()
+    }
+}
+
+/// Hands records to a thread that encodes and writes them, and finishes the file when dropped
+#[derive(Resource)]
+pub struct McapRecorder {
+    records: Records,
+    /// Records the writer could not keep up with
+    dropped: AtomicUsize,
+    outgoing: Monitor,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl McapRecorder {
+    fn start(path: &Path, outgoing: Monitor) -> Result<Self> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create MCAP {}: {e}", path.display()))?;
+        let mut mcap = McapWriter::new(BufWriter::new(file))?;
+        let mut schemas = HashMap::<&str, u16>::default();
+        let mut channels = HashMap::default();
+        for topic in Topic::ALL {
+            let (name, schema) = topic.schema();
+            let (schema_encoding, message_encoding) = schema.encodings();
+            let schema = match schemas.get(name) {
+                Some(&id) => id,
+                None => {
+                    let id = mcap.add_schema(name, schema_encoding, &schema.data()?)?;
+                    schemas.insert(name, id);
+                    id
+                }
+            };
+            channels.insert(
+                topic,
+                mcap.add_channel(schema, topic.name(), message_encoding)?,
+            );
+        }
+        let records = Records::new();
+        let writer = thread::Builder::new()
+            .name("MCAP writer".to_owned())
+            .spawn({
+                let records = records.clone();
+                move || {
+                    if let Err(e) = write_records(mcap, &channels, &records) {
+                        error!("Failed to write MCAP: {e}");
+                    }
+                    // Keeps records from piling up once the writer has given up
+                    records.close();
+                }
+            })?;
+        Ok(Self {
+            records,
+            dropped: AtomicUsize::new(0),
+            outgoing,
+            writer: Some(writer),
+        })
+    }
+
+    fn record(&self, topic: Topic, time: Duration, payload: Payload) {
+        let dropped = self.records.push(Record {
+            topic,
+            time,
+            payload,
+        });
+        self.dropped.fetch_add(dropped, Ordering::Relaxed);
+    }
+}
+
+impl Drop for McapRecorder {
+    fn drop(&mut self) {
+        // Closing the queues lets the writer finish the file
+        self.records.close();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        let dropped = *self.dropped.get_mut();
+        if dropped > 0 {
+            warn!("Dropped {dropped} MCAP records the writer could not keep up with");
+        }
+    }
+}
+
+/// How often the writer flushes, bounding how much a crash loses
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+fn write_records(
+    mut mcap: McapWriter<BufWriter<File>>,
+    channels: &HashMap<Topic, u16>,
+    records: &Records,
+) -> Result {
+    let mut last_flush = Instant::now();
+    loop {
+        if last_flush.elapsed() >= FLUSH_INTERVAL {
+            mcap.flush()?;
+            last_flush = Instant::now();
+        }
+        let Record {
+            topic,
+            time,
+            payload,
+        } = match records.try_recv() {
+            Some(record) => record,
+            None if records.is_closed() => break,
+            None => match records.recv_timeout(FLUSH_INTERVAL) {
+                Some(record) => record,
+                None => continue,
+            },
+        };
+        let message = match payload {
+            Payload::Json(message) => serde_json::to_vec(&message)?,
+            Payload::Image(image, encoding) => image_ros1(topic, time, image, encoding)?,
+        };
+        mcap.write_message(channels[&topic], time.as_nanos() as u64, &message)?;
+    }
+    mcap.finish()?;
+    Ok(())
+}
+
+fn record_outgoing(recorder: Res<McapRecorder>, clock: Res<SimClock>, time: Res<Time>) {
+    // Messages are logged at the sim time they were stamped with, rather than when they are seen
+    let log_time = |stamp: Stamp| match clock.timestamps {
+        TimestampClock::Sim => Duration::from_secs_f64(stamp.time),
+        TimestampClock::Wall => time.elapsed(),
+    };
+    while let Some(Queued {
+        message, encoding, ..
+    }) = recorder.outgoing.try_recv()
+    {
+        let (topic, time, payload) = match message {
+            OutgoingMessage::Sensors(sensors) => (
+                Topic::Sensors,
+                log_time(sensors.stamp),
+                Payload::Json(sensors_json(&sensors)),
+            ),
+            OutgoingMessage::Imu(stamp, ins, pimu) => {
+                let message = json!({
+                    "tick": stamp.tick,
+                    "imu_ins": { "theta": ins.theta },
+                    "imu_pimu": imu_pimu_json(&pimu),
+                });
+                (Topic::Imu, log_time(stamp), Payload::Json(message))
+            }
+            OutgoingMessage::Dvl(stamp, dvl) => {
+                let message = json!({ "tick": stamp.tick, "dvl": dvl_json(&dvl) });
+                (Topic::Dvl, log_time(stamp), Payload::Json(message))
+            }
+            OutgoingMessage::Depth(stamp, depth) => {
+                let message = json!({ "tick": stamp.tick, "depth": depth });
+                (Topic::Depth, log_time(stamp), Payload::Json(message))
+            }
+            OutgoingMessage::BotcamImage(image) => (
+                Topic::image(MessageKind::BotcamImage, encoding),
+                log_time(image.stamp),
+                Payload::Image(image, encoding),
+            ),
+            OutgoingMessage::ZedImage(image) => (
+                Topic::image(MessageKind::ZedImage, encoding),
+                log_time(image.stamp),
+                Payload::Image(image, encoding),
+            ),
+            OutgoingMessage::MlTarget(stamp, targets, size) => {
+                let targets: Vec<_> = targets
+                    .iter()
+                    .map(|target| {
+                        json!({
+                            "kind": format!("{:?}", target.kind),
+                            "left": target.left,
+                            "top": target.top,
+                            "right": target.right,
+                            "bottom": target.bottom,
+                        })
+                    })
+                    .collect();
+                let message = json!({
+                    "tick": stamp.tick,
+                    "targets": targets,
+                    "width": size.x,
+                    "height": size.y,
+                });
+                (Topic::MlTargets, log_time(stamp), Payload::Json(message))
+            }
+            // Ground truth is recorded every frame by `record_ground_truth`
+            OutgoingMessage::Hello(_)
+            | OutgoingMessage::ShmImage(_)
+            | OutgoingMessage::GroundTruth(_) => continue,
+        };
+        recorder.record(topic, time, payload);
+    }
+}
+
+fn record_thrusters(
+    recorder: Res<McapRecorder>,
+    thrusters: Query<(&ThrusterOf, &ThrusterTarget, &ThrusterState, &ThrusterForce)>,
+    time: Res<Time>,
+) {
+    let mut thrusters: Vec<_> = thrusters.iter().collect();
+    thrusters.sort_by_key(|(thruster, ..)| thruster.id);
+    let thrusters: Vec<_> = thrusters
+        .into_iter()
+        .map(|(thruster, target, state, force)| {
+            json!({
+                "id": thruster.id,
+                "target": target.target_output,
+                "output": state.output(),
+                "force": force.force(),
+            })
+        })
+        .collect();
+    recorder.record(
+        Topic::Thrusters,
+        time.elapsed(),
+        Payload::Json(json!({ "thrusters": thrusters })),
+    );
+}
+
+fn record_ground_truth(
+    recorder: Res<McapRecorder>,
+    subs: Query<(&Position, &Rotation), With<SubControls>>,
+    frames: Res<Frames>,
+    time: Res<Time>,
+) {
+    let time = time.elapsed();
+    for (position, rotation) in subs {
+        let position = frames.world_from_bevy(position.0);
+        let message = pose(time, position, frames.orientation_from_bevy(rotation.0));
+        recorder.record(Topic::GroundTruth, time, Payload::Json(message));
+    }
+}
+
+fn record_localization(
+    recorder: Res<McapRecorder>,
+    mut incoming: EventReader<IncomingMessage>,
+    time: Res<Time>,
+) {
+    let time = time.elapsed();
+    for message in incoming.read() {
+        let IncomingMessage::LocalizationEstimate {
+            rotation,
+            position,
+            velocity,
+        } = message
+        else {
+            continue;
+        };
+        let mut message = pose(time, *position, Quat::from_mat3(rotation));
+        message["velocity"] = vector3(*velocity);
+        recorder.record(Topic::Localization, time, Payload::Json(message));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_frames_are_recorded_as_ros1_images() {
+        let image = ImageMessage::raw(Stamp::default(), 2, 1, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        let time = Duration::new(3, 500);
+        let message = image_ros1(Topic::ZedImage, time, image, ImageEncoding::Qoi).unwrap();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&0u32.to_le_bytes());
+        expected.extend_from_slice(&3u32.to_le_bytes());
+        expected.extend_from_slice(&500u32.to_le_bytes());
+        expected.extend_from_slice(&3u32.to_le_bytes());
+        expected.extend_from_slice(b"zed");
+        expected.extend_from_slice(&1u32.to_le_bytes());
+        expected.extend_from_slice(&2u32.to_le_bytes());
+        expected.extend_from_slice(&5u32.to_le_bytes());
+        expected.extend_from_slice(b"rgba8");
+        expected.push(0);
+        expected.extend_from_slice(&8u32.to_le_bytes());
+        expected.extend_from_slice(&8u32.to_le_bytes());
+        expected.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn compressed_frames_go_on_their_own_topic() {
+        assert_eq!(
+            Topic::image(MessageKind::BotcamImage, ImageEncoding::Jpeg),
+            Topic::BotcamCompressed
+        );
+        assert_eq!(
+            Topic::image(MessageKind::BotcamImage, ImageEncoding::Qoi),
+            Topic::BotcamImage
+        );
+        let image = ImageMessage::raw(Stamp::default(), 1, 1, vec![255; 4]);
+        let message = image_ros1(
+            Topic::BotcamCompressed,
+            Duration::ZERO,
+            image,
+            ImageEncoding::Png,
+        )
+        .unwrap();
+        // After a header with a 6 byte frame id come the format and the PNG
+        assert_eq!(message[22..29], [3, 0, 0, 0, b'p', b'n', b'g']);
+        assert_eq!(message[29..33], (message.len() as u32 - 33).to_le_bytes());
+        assert!(message[33..].starts_with(b"\x89PNG"));
+    }
+}
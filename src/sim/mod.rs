@@ -9,8 +9,10 @@ use bevy::{
     window::{PresentMode, PrimaryWindow},
 };
 use physics::SubPhysicsPlugin;
+use rand::{SeedableRng as _, rngs::StdRng};
 use scene::startup_spawner;
 use sub::SubPlugin;
+use subsimgpt2::config::Config;
 
 use crate::frustum_gizmo::FrustumGizmoConfigGroup;
 
@@ -26,7 +28,16 @@ impl Plugin for SimPlugin {
             render_layers: GIZMO_RENDER_LAYER,
             ..default()
         };
+        let seed = app
+            .world()
+            .get_resource::<Config>()
+            .map(|config| config.get("seed"))
+            .transpose()
+            .expect("Seed should be valid")
+            .flatten()
+            .unwrap_or_default();
         app.add_plugins((SubPhysicsPlugin::default(), SubPlugin::default()))
+            .insert_resource(SimRng::from_seed(seed))
             .add_systems(Startup, (startup_spawner, disable_vsync))
             .add_systems(Update, |mut gizmos: Gizmos| {
                 gizmos.axes(Transform::default(), 0.2)
@@ -47,6 +58,16 @@ impl Plugin for SimPlugin {
 #[reflect(Component, Debug)]
 pub struct ViewCamera;
 
+/// The source of all randomness in the sim, so that runs with the same seed are repeatable
+#[derive(Debug, Clone, Resource, Deref, DerefMut)]
+pub struct SimRng(StdRng);
+
+impl SimRng {
+    pub fn from_seed(seed: u64) -> Self {
+        Self(StdRng::seed_from_u64(seed))
+    }
+}
+
 fn disable_vsync(mut window: Query<&mut Window, With<PrimaryWindow>>) -> Result {
     let mut window = window.single_mut()?;
     window.present_mode = PresentMode::AutoNoVsync;
@@ -4,11 +4,10 @@ use avian3d::prelude::{
     PhysicsSet,
 };
 use bevy::prelude::*;
-use rand::{SeedableRng as _, rngs::StdRng};
 
 use crate::utils::add_forces;
 
-use super::sub::thruster::ThrusterOf;
+use super::{SimRng, sub::thruster::ThrusterOf};
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct SubPhysicsPlugin;
@@ -99,10 +98,8 @@ fn buoyancy(
     water: Query<(&GlobalTransform, &WaterCollider)>,
     mut gizmos: Gizmos,
     mut commands: Commands,
-    mut rng: Local<Option<StdRng>>,
+    mut rng: ResMut<SimRng>,
 ) -> Result {
-    // TODO: Is this a sufficiently deterministic rng?
-    let mut rng = rng.get_or_insert_with(|| StdRng::from_seed([0; 32]));
     let (water_transform, water_cuboid) = water.single()?;
     let water_inverse = water_transform.affine().inverse();
     for (transform, buoyancy, mass, com, entity) in subs {
@@ -112,7 +109,7 @@ fn buoyancy(
 
         let force_per_sample = -gravity_force / (samples.count as f32) * buoyancy.buoyancy_factor;
         for _ in 0..samples.count {
-            let local_sample = buoyancy.sample_interior(&mut rng);
+            let local_sample = buoyancy.sample_interior(&mut **rng);
             let global_sample = transform.transform_point(local_sample);
             let water_local = water_inverse.transform_point(global_sample);
             let underwater = water_cuboid.closest_point(water_local) == water_local;
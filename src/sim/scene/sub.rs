@@ -16,8 +16,8 @@ use bevy::{
 use crate::{
     frustum_gizmo::ShowFrustumGizmo,
     hal::{
-        BotCamImage, BottomCamera, CameraEnabled, CameraTimer, DepthSensor, Dvl, ImageExportSource,
-        Imu, MLTargets, ZedCamera, ZedImage,
+        BOTCAM_SIZE, BotCamImage, BottomCamera, CameraEnabled, CameraTimer, DepthSensor, Dvl,
+        ImageExportSource, Imu, MLTargets, ZED_SIZE, ZedCamera, ZedImage,
     },
     sim::{
         physics::{BuoyancySamples, SubBuoyancy, WaterResistance},
@@ -79,13 +79,8 @@ pub fn spawn_sub(
             children![
                 (
                     Imu::default(),
-                    Transform::from_rotation(Quat::from_euler(
-                        EulerRot::ZYX,
-                        0.0,
-                        -FRAC_PI_2,
-                        -FRAC_PI_2
-                    ))
-                    .with_translation(Vec3::new(-0.15, 0., -0.05)),
+                    // Mounted square with the body, so it measures in the body frame
+                    Transform::from_translation(Vec3::new(-0.15, 0., -0.05)),
                     Name::new("IMU"),
                 ),
                 (Dvl::default(), Transform::default(), Name::new("DVL")),
@@ -148,8 +143,8 @@ fn spawn_zed(
 ) -> ZedCamEntity {
     let mut image = Image::new_fill(
         render_resource::Extent3d {
-            width: 1280,
-            height: 480,
+            width: ZED_SIZE.x,
+            height: ZED_SIZE.y,
             ..default()
         },
         TextureDimension::D2,
@@ -199,8 +194,8 @@ fn spawn_botcam(
 ) -> Entity {
     let mut image = Image::new_fill(
         render_resource::Extent3d {
-            width: 960,
-            height: 540,
+            width: BOTCAM_SIZE.x,
+            height: BOTCAM_SIZE.y,
             ..default()
         },
         TextureDimension::D2,
@@ -4,7 +4,7 @@ use std::f32::consts::{FRAC_PI_2, PI};
 
 use avian3d::prelude::{AngularVelocity, LinearVelocity};
 use bevy::prelude::*;
-use rand::{Rng as _, thread_rng};
+use rand::Rng as _;
 use thruster::{
     ThrusterForce, ThrusterOf, ThrusterParams, ThrusterState, ThrusterTarget, Thrusters,
     debug_thruster_states, thruster_physics, update_thruster_forces, update_thruster_states,
@@ -12,7 +12,7 @@ use thruster::{
 
 use crate::control::ControlState;
 
-use super::physics::SubPhysicsSet;
+use super::{SimRng, physics::SubPhysicsSet};
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct SubPlugin;
@@ -46,6 +46,11 @@ impl Plugin for SubPlugin {
             (set_teleop_state, reset_sub, coin_flip_sub).run_if(in_state(ControlState::Unfocused)),
         )
         .add_systems(Update, sub_controls.run_if(in_state(TeleopState::Teleop)))
+        .add_systems(
+            Update,
+            apply_sub_resets.after(reset_sub).after(coin_flip_sub),
+        )
+        .add_event::<ResetSub>()
         .add_sub_state::<TeleopState>()
         .register_type::<(
             SubControls,
@@ -154,31 +159,64 @@ fn sub_controls(
     Ok(())
 }
 
-pub fn reset_sub(
-    mut sub: Query<(&mut Transform, &mut LinearVelocity, &mut AngularVelocity), With<SubControls>>,
-    keyboard_input: Res<ButtonInput<KeyCode>>,
-) -> Result {
+/// Where the sub starts a run
+const START_TRANSLATION: Vec3 = Vec3::new(1., -0.2, 0.);
+
+/// Moves the sub, stopping it unless given new velocities
+#[derive(Debug, Clone, Copy, PartialEq, Event)]
+pub enum ResetSub {
+    /// Back to the start
+    Start,
+    /// Back to the start, facing one of the two coin-flip headings at random
+    CoinFlip,
+    Teleport {
+        transform: Transform,
+        linear_velocity: Vec3,
+        angular_velocity: Vec3,
+    },
+}
+
+pub fn reset_sub(keyboard_input: Res<ButtonInput<KeyCode>>, mut resets: EventWriter<ResetSub>) {
     if keyboard_input.just_pressed(KeyCode::KeyR) {
-        let (mut transform, mut vel, mut ang_vel) = sub.single_mut()?;
-        *transform = Transform::from_xyz(1., -0.2, 0.);
-        *vel = default();
-        *ang_vel = default();
+        resets.write(ResetSub::Start);
+    }
+}
+
+pub fn coin_flip_sub(keyboard_input: Res<ButtonInput<KeyCode>>, mut resets: EventWriter<ResetSub>) {
+    if keyboard_input.just_pressed(KeyCode::KeyC) {
+        resets.write(ResetSub::CoinFlip);
     }
-    Ok(())
 }
 
-pub fn coin_flip_sub(
+pub fn apply_sub_resets(
+    mut resets: EventReader<ResetSub>,
     mut sub: Query<(&mut Transform, &mut LinearVelocity, &mut AngularVelocity), With<SubControls>>,
-    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut rng: ResMut<SimRng>,
 ) -> Result {
-    if keyboard_input.just_pressed(KeyCode::KeyC) {
+    for reset in resets.read() {
         let (mut transform, mut vel, mut ang_vel) = sub.single_mut()?;
-        let mut rand = thread_rng();
-        let coin_flip: bool = rand.r#gen();
-        let angle = if coin_flip { -FRAC_PI_2 } else { -PI };
-        *transform = Transform::from_xyz(1., -0.2, 0.).with_rotation(Quat::from_rotation_y(angle));
-        *vel = default();
-        *ang_vel = default();
+        (*transform, vel.0, ang_vel.0) = match *reset {
+            ResetSub::Start => (
+                Transform::from_translation(START_TRANSLATION),
+                default(),
+                default(),
+            ),
+            ResetSub::CoinFlip => {
+                let coin_flip: bool = rng.r#gen();
+                let angle = if coin_flip { -FRAC_PI_2 } else { -PI };
+                (
+                    Transform::from_translation(START_TRANSLATION)
+                        .with_rotation(Quat::from_rotation_y(angle)),
+                    default(),
+                    default(),
+                )
+            }
+            ResetSub::Teleport {
+                transform,
+                linear_velocity,
+                angular_velocity,
+            } => (transform, linear_velocity, angular_velocity),
+        };
     }
     Ok(())
 }
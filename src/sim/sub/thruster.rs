@@ -34,12 +34,24 @@ pub struct ThrusterState {
     output: f32,
 }
 
+impl ThrusterState {
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Component, Reflect)]
 #[reflect(Component, PartialEq, Debug)]
 pub struct ThrusterForce {
     force: f32,
 }
 
+impl ThrusterForce {
+    pub fn force(&self) -> f32 {
+        self.force
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Component, Reflect)]
 #[reflect(Component, PartialEq, Debug)]
 pub struct ThrusterParams {
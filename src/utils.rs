@@ -31,14 +31,3 @@ pub fn add_forces(a: &ExternalForce, b: &ExternalForce, persistence: bool) -> Ex
     let torque = a.torque() + b.torque();
     (force, persistence, torque).into()
 }
-
-pub fn flatten_array<const M: usize, const N: usize, const O: usize>(
-    input: [[u8; N]; M],
-) -> [u8; O] {
-    assert_eq!(M * N, O);
-    let mut out = [0; O];
-    for i in 0..M {
-        out[(i * N)..((i + 1) * N)].copy_from_slice(&input[i]);
-    }
-    out
-}
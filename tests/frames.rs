@@ -0,0 +1,160 @@
+use std::f32::consts::FRAC_PI_2;
+
+use bevy::math::{Quat, Vec3};
+use proptest::prelude::*;
+use subsimgpt2::frames::{BodyFrame, Frames, WorldFrame};
+
+const ALL: [Frames; 4] = [
+    Frames {
+        world: WorldFrame::Ned,
+        body: BodyFrame::Frd,
+    },
+    Frames {
+        world: WorldFrame::Ned,
+        body: BodyFrame::Flu,
+    },
+    Frames {
+        world: WorldFrame::Enu,
+        body: BodyFrame::Frd,
+    },
+    Frames {
+        world: WorldFrame::Enu,
+        body: BodyFrame::Flu,
+    },
+];
+
+const NED_FRD: Frames = ALL[0];
+const ENU_FLU: Frames = ALL[3];
+
+// The sub faces +X with +Z on its right, and north is +X
+const FORWARD: Vec3 = Vec3::X;
+const RIGHT: Vec3 = Vec3::Z;
+const UP: Vec3 = Vec3::Y;
+const NORTH: Vec3 = Vec3::X;
+const EAST: Vec3 = Vec3::Z;
+
+fn assert_close(actual: impl Into<Vec3>, expected: impl Into<Vec3>) {
+    let (actual, expected) = (actual.into(), expected.into());
+    assert!(
+        actual.abs_diff_eq(expected, 1e-5),
+        "{actual} is not {expected}"
+    );
+}
+
+#[test]
+fn world_axes() {
+    let ned = NED_FRD;
+    assert_close(ned.world_to_bevy(Vec3::X), NORTH);
+    assert_close(ned.world_to_bevy(Vec3::Y), EAST);
+    assert_close(ned.world_to_bevy(Vec3::Z), -UP);
+
+    let enu = ENU_FLU;
+    assert_close(enu.world_to_bevy(Vec3::X), EAST);
+    assert_close(enu.world_to_bevy(Vec3::Y), NORTH);
+    assert_close(enu.world_to_bevy(Vec3::Z), UP);
+}
+
+#[test]
+fn body_axes() {
+    let frd = NED_FRD;
+    assert_close(frd.body_to_bevy(Vec3::X), FORWARD);
+    assert_close(frd.body_to_bevy(Vec3::Y), RIGHT);
+    assert_close(frd.body_to_bevy(Vec3::Z), -UP);
+
+    let flu = ENU_FLU;
+    assert_close(flu.body_to_bevy(Vec3::X), FORWARD);
+    assert_close(flu.body_to_bevy(Vec3::Y), -RIGHT);
+    assert_close(flu.body_to_bevy(Vec3::Z), UP);
+}
+
+#[test]
+fn level_sub_facing_north() {
+    assert_close(NED_FRD.euler_from_bevy(Quat::IDENTITY), [0., 0., 0.]);
+    // ENU yaw is measured from east, counterclockwise
+    assert_close(ENU_FLU.euler_from_bevy(Quat::IDENTITY), [0., 0., FRAC_PI_2]);
+}
+
+#[test]
+fn ned_frd_euler_angles() {
+    let angle = 0.3;
+    // Turning right, about Bevy's up axis
+    assert_close(
+        NED_FRD.euler_from_bevy(Quat::from_rotation_y(-angle)),
+        [0., 0., angle],
+    );
+    // Nose up, about the sub's left axis
+    assert_close(
+        NED_FRD.euler_from_bevy(Quat::from_rotation_z(angle)),
+        [0., angle, 0.],
+    );
+    // Starboard down, about the sub's forward axis
+    assert_close(
+        NED_FRD.euler_from_bevy(Quat::from_rotation_x(angle)),
+        [angle, 0., 0.],
+    );
+}
+
+#[test]
+fn enu_flu_euler_angles() {
+    let angle = 0.3;
+    // Turning left is positive yaw
+    assert_close(
+        ENU_FLU.euler_from_bevy(Quat::from_rotation_y(angle)),
+        [0., 0., FRAC_PI_2 + angle],
+    );
+    // Nose down is positive pitch
+    assert_close(
+        ENU_FLU.euler_from_bevy(Quat::from_rotation_z(-angle)),
+        [0., angle, FRAC_PI_2],
+    );
+    // Starboard down is still positive roll
+    assert_close(
+        ENU_FLU.euler_from_bevy(Quat::from_rotation_x(angle)),
+        [angle, 0., FRAC_PI_2],
+    );
+}
+
+#[test]
+fn orientation_rotates_body_into_world() {
+    let rotation = Quat::from_rotation_y(-0.7) * Quat::from_rotation_z(0.2);
+    for frames in ALL {
+        let orientation = frames.orientation_from_bevy(rotation);
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            let bevy = rotation * frames.body_to_bevy(axis);
+            assert_close(orientation * axis, frames.world_from_bevy(bevy));
+        }
+    }
+}
+
+fn rotation() -> impl Strategy<Value = Quat> {
+    (prop::array::uniform3(-1f32..1.), -3f32..3.).prop_filter_map(
+        "axis should not be zero",
+        |(axis, angle)| {
+            let axis = Vec3::from_array(axis).try_normalize()?;
+            Some(Quat::from_axis_angle(axis, angle))
+        },
+    )
+}
+
+fn vector() -> impl Strategy<Value = Vec3> {
+    prop::array::uniform3(-100f32..100.).prop_map(Vec3::from_array)
+}
+
+proptest! {
+    #[test]
+    fn orientation_round_trips(rotation in rotation()) {
+        for frames in ALL {
+            let round_trip = frames.orientation_to_bevy(frames.orientation_from_bevy(rotation));
+            // Either sign is the same rotation
+            prop_assert!(round_trip.dot(rotation).abs() > 1. - 1e-5);
+        }
+    }
+
+    #[test]
+    fn vector_round_trips(vector in vector()) {
+        for frames in ALL {
+            prop_assert!(frames.world_to_bevy(frames.world_from_bevy(vector)).abs_diff_eq(vector, 1e-4));
+            prop_assert!(frames.body_to_bevy(frames.body_from_bevy(vector)).abs_diff_eq(vector, 1e-4));
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use bevy::math::Vec3;
+use subsimgpt2::{
+    frames::Frames,
+    inertial::{EARTH_RATE, RigidMotion, earth_rotation},
+};
+
+const GRAVITY: Vec3 = Vec3::new(0.0, -9.81, 0.0);
+const DT: f32 = 1.0 / 64.0;
+
+fn assert_close(actual: Vec3, expected: Vec3) {
+    assert!(
+        actual.abs_diff_eq(expected, 1e-4),
+        "{actual} is not {expected}"
+    );
+}
+
+#[test]
+fn reads_one_g_up_at_rest() {
+    let rest = RigidMotion::default();
+    let force = rest.specific_force(Vec3::new(-0.15, 0.0, -0.05), GRAVITY, Vec3::ZERO, DT);
+    assert_close(force, Vec3::new(0.0, 9.81, 0.0));
+    // Down is +z in FRD
+    assert_close(
+        Frames::default().body_from_bevy(force),
+        Vec3::new(0.0, 0.0, -9.81),
+    );
+    assert_close(rest.angular_increment(Vec3::ZERO, DT), Vec3::ZERO);
+}
+
+#[test]
+fn falling_reads_nothing() {
+    let falling = RigidMotion {
+        previous_velocity: Vec3::ZERO,
+        velocity: GRAVITY * DT,
+        ..Default::default()
+    };
+    let force = falling.specific_force(Vec3::X, GRAVITY, Vec3::ZERO, DT);
+    assert_close(force, Vec3::ZERO);
+}
+
+#[test]
+fn steady_rotation_is_centripetal() {
+    let omega = Vec3::new(0.0, 2.0, 0.0);
+    let spinning = RigidMotion {
+        previous_angular_velocity: omega,
+        angular_velocity: omega,
+        ..Default::default()
+    };
+    // Pulled in towards the axis by ω²r
+    let force = spinning.specific_force(Vec3::X, GRAVITY, Vec3::ZERO, DT);
+    assert_close(force, Vec3::new(-4.0, 9.81, 0.0));
+    // Nothing at the center of mass
+    let force = spinning.specific_force(Vec3::ZERO, GRAVITY, Vec3::ZERO, DT);
+    assert_close(force, Vec3::new(0.0, 9.81, 0.0));
+    assert_close(spinning.angular_increment(Vec3::ZERO, DT), omega * DT);
+}
+
+#[test]
+fn spinning_up_is_tangential() {
+    let alpha = Vec3::new(0.0, 1.0, 0.0);
+    let spinning_up = RigidMotion {
+        previous_angular_velocity: Vec3::ZERO,
+        angular_velocity: alpha * DT,
+        ..Default::default()
+    };
+    let force = spinning_up.specific_force(Vec3::X, GRAVITY, Vec3::ZERO, DT);
+    // α × r, plus the centripetal part from the speed it has picked up
+    assert_close(force, Vec3::new(-DT * DT, 9.81, -1.0));
+}
+
+#[test]
+fn earth_rotation_at_the_poles_and_equator() {
+    assert_close(earth_rotation(90.0), Vec3::new(0.0, EARTH_RATE, 0.0));
+    assert_close(earth_rotation(0.0), Vec3::new(EARTH_RATE, 0.0, 0.0));
+    let rest = RigidMotion::default();
+    assert_close(
+        rest.angular_increment(earth_rotation(90.0), 1.0),
+        Vec3::new(0.0, EARTH_RATE, 0.0),
+    );
+}
+
+#[test]
+fn heading_east_on_the_equator_lightens_gravity() {
+    let speed = 100.0;
+    let east = Vec3::new(0.0, 0.0, speed);
+    let cruising = RigidMotion {
+        previous_velocity: east,
+        velocity: east,
+        ..Default::default()
+    };
+    let force = cruising.specific_force(Vec3::ZERO, GRAVITY, earth_rotation(0.0), DT);
+    assert_close(force, Vec3::new(0.0, 9.81 - 2.0 * EARTH_RATE * speed, 0.0));
+}
@@ -0,0 +1,447 @@
+use bevy::math::{Mat3, Vec2, Vec3};
+use futures_lite::future::block_on;
+use proptest::prelude::*;
+use subsimgpt2::protocol::{
+    CameraInfo, DecodeError, Dvl, EncodeError, GroundTruth, Hello, ImageEncoding, ImageMessage,
+    ImuINS, ImuPIMU, IncomingMessage, MLTargetData, MLTargetKind, Message, MessageKind,
+    OutgoingMessage, SIM_CONNECTION_KINDS, SensorInfo, SensorMessage, SensorSet, Stamp,
+    log::LogRecord,
+    read_frame,
+    shm::{self, ShmImage, ShmRingReader, ShmRingWriter},
+};
+
+fn finite() -> impl Strategy<Value = f32> {
+    -1e6f32..1e6
+}
+
+fn vec3() -> impl Strategy<Value = Vec3> {
+    prop::array::uniform3(finite()).prop_map(Vec3::from_array)
+}
+
+fn image_encoding() -> impl Strategy<Value = ImageEncoding> {
+    prop_oneof![
+        Just(ImageEncoding::Raw),
+        Just(ImageEncoding::Qoi),
+        Just(ImageEncoding::Png),
+        Just(ImageEncoding::Jpeg),
+    ]
+}
+
+fn ml_target_kind() -> impl Strategy<Value = MLTargetKind> {
+    prop_oneof![
+        Just(MLTargetKind::GateRed),
+        Just(MLTargetKind::GateBlue),
+        Just(MLTargetKind::None),
+    ]
+}
+
+fn hello() -> impl Strategy<Value = Hello> {
+    let camera = (
+        prop_oneof![Just(MessageKind::BotcamImage), Just(MessageKind::ZedImage)],
+        any::<u32>(),
+        any::<u32>(),
+        finite(),
+    )
+        .prop_map(|(kind, width, height, rate)| CameraInfo {
+            kind,
+            width,
+            height,
+            rate,
+        });
+    let sensor = || {
+        prop::option::of(
+            (finite(), any::<bool>())
+                .prop_map(|(rate, own_message)| SensorInfo { rate, own_message }),
+        )
+    };
+    (
+        any::<u16>(),
+        prop::collection::vec(any::<u8>(), 0..16),
+        prop::collection::vec(camera, 0..4),
+        prop::collection::vec(ml_target_kind(), 0..4),
+        (sensor(), sensor(), sensor(), any::<bool>()),
+    )
+        .prop_map(
+            |(version, thruster_ids, cameras, ml_target_kinds, (depth, dvl, imu, ground_truth))| {
+                Hello {
+                    version,
+                    thruster_ids,
+                    cameras,
+                    ml_target_kinds,
+                    sensors: SensorSet {
+                        depth,
+                        dvl,
+                        imu,
+                        ground_truth,
+                    },
+                }
+            },
+        )
+}
+
+fn stamp() -> impl Strategy<Value = Stamp> {
+    (
+        any::<f64>().prop_filter("finite", |t| t.is_finite()),
+        any::<u64>(),
+    )
+        .prop_map(|(time, tick)| Stamp { time, tick })
+}
+
+fn dvl() -> impl Strategy<Value = Dvl> {
+    (prop::array::uniform4(finite()), any::<[bool; 4]>()).prop_map(|(dvl, beam_valid)| Dvl {
+        velocity_a: dvl[0],
+        velocity_b: dvl[1],
+        velocity_c: dvl[2],
+        altitude: dvl[3],
+        beam_valid,
+    })
+}
+
+fn imu() -> impl Strategy<Value = (ImuINS, ImuPIMU)> {
+    (
+        prop::array::uniform3(finite()),
+        prop::array::uniform3(finite()),
+        prop::array::uniform3(finite()),
+        finite(),
+    )
+        .prop_map(|(theta, dtheta, dvel, dt)| (ImuINS { theta }, ImuPIMU { dtheta, dvel, dt }))
+}
+
+fn sensors() -> impl Strategy<Value = SensorMessage> {
+    (stamp(), finite(), dvl(), imu()).prop_map(|(stamp, depth, dvl, (imu_ins, imu_pimu))| {
+        SensorMessage {
+            stamp,
+            depth,
+            dvl,
+            imu_ins,
+            imu_pimu,
+        }
+    })
+}
+
+fn image_message() -> impl Strategy<Value = ImageMessage> {
+    (
+        stamp(),
+        any::<u32>(),
+        any::<u32>(),
+        image_encoding(),
+        prop::collection::vec(any::<u8>(), 0..256),
+    )
+        .prop_map(|(stamp, width, height, encoding, data)| ImageMessage {
+            stamp,
+            width,
+            height,
+            encoding,
+            data,
+        })
+}
+
+fn shm_image() -> impl Strategy<Value = ShmImage> {
+    (
+        prop_oneof![Just(MessageKind::BotcamImage), Just(MessageKind::ZedImage)],
+        stamp(),
+        any::<(u32, u32)>(),
+        image_encoding(),
+        any::<(u32, u64, u64)>(),
+    )
+        .prop_map(
+            |(camera, stamp, (width, height), encoding, (slot, sequence, len))| ShmImage {
+                camera,
+                stamp,
+                width,
+                height,
+                encoding,
+                slot,
+                sequence,
+                len,
+            },
+        )
+}
+
+fn outgoing() -> impl Strategy<Value = OutgoingMessage> {
+    let target = (ml_target_kind(), prop::array::uniform4(finite())).prop_map(
+        |(kind, [left, top, right, bottom])| MLTargetData {
+            kind,
+            left,
+            top,
+            right,
+            bottom,
+        },
+    );
+    prop_oneof![
+        sensors().prop_map(OutgoingMessage::Sensors),
+        (stamp(), imu()).prop_map(|(stamp, (ins, pimu))| OutgoingMessage::Imu(stamp, ins, pimu)),
+        (stamp(), dvl()).prop_map(|(stamp, dvl)| OutgoingMessage::Dvl(stamp, dvl)),
+        (stamp(), finite()).prop_map(|(stamp, depth)| OutgoingMessage::Depth(stamp, depth)),
+        image_message().prop_map(OutgoingMessage::BotcamImage),
+        image_message().prop_map(OutgoingMessage::ZedImage),
+        (
+            stamp(),
+            prop::collection::vec(target, 0..8),
+            prop::array::uniform2(finite())
+        )
+            .prop_map(|(stamp, targets, size)| OutgoingMessage::MlTarget(
+                stamp,
+                targets.into(),
+                Vec2::from_array(size)
+            )),
+        hello().prop_map(OutgoingMessage::Hello),
+        shm_image().prop_map(OutgoingMessage::ShmImage),
+        (
+            stamp(),
+            prop::array::uniform9(finite()),
+            vec3(),
+            vec3(),
+            vec3()
+        )
+            .prop_map(|(stamp, rotation, position, velocity, angular_velocity)| {
+                OutgoingMessage::GroundTruth(GroundTruth {
+                    stamp,
+                    rotation: Mat3::from_cols_array(&rotation),
+                    position,
+                    velocity,
+                    angular_velocity,
+                })
+            }),
+    ]
+}
+
+fn incoming() -> impl Strategy<Value = IncomingMessage> {
+    prop_oneof![
+        prop::collection::vec((any::<u8>(), finite()), 0..16)
+            .prop_map(|thrusts| IncomingMessage::Motors(thrusts.into())),
+        any::<bool>().prop_map(IncomingMessage::BotcamOn),
+        any::<bool>().prop_map(IncomingMessage::ZedOn),
+        image_encoding().prop_map(IncomingMessage::BotcamEncoding),
+        image_encoding().prop_map(IncomingMessage::ZedEncoding),
+        (prop::array::uniform9(finite()), vec3(), vec3()).prop_map(
+            |(rotation, position, velocity)| IncomingMessage::LocalizationEstimate {
+                rotation: Mat3::from_cols_array(&rotation),
+                position,
+                velocity,
+            }
+        ),
+        hello().prop_map(IncomingMessage::Hello),
+        Just(IncomingMessage::SensorAck),
+        Just(IncomingMessage::ResetSub),
+        Just(IncomingMessage::CoinFlip),
+        (prop::array::uniform9(finite()), vec3(), vec3(), vec3()).prop_map(
+            |(rotation, position, velocity, angular_velocity)| IncomingMessage::TeleportSub {
+                rotation: Mat3::from_cols_array(&rotation),
+                position,
+                velocity,
+                angular_velocity,
+            }
+        ),
+        Just(IncomingMessage::Pause),
+        Just(IncomingMessage::Resume),
+        any::<u32>().prop_map(IncomingMessage::Step),
+        any::<u64>().prop_map(IncomingMessage::Seed),
+    ]
+}
+
+/// Round-trips through the async frame reader as well as [`Message::from_frame`]
+fn round_trip<M: Message + PartialEq + std::fmt::Debug>(message: &M) {
+    let frame = message.to_frame().unwrap();
+    assert_eq!(M::from_frame(&frame).as_ref(), Ok(message));
+
+    let mut stream = frame.as_slice();
+    let read = block_on(read_frame(&mut stream)).unwrap().unwrap();
+    assert!(stream.is_empty());
+    assert_eq!(read.kind, message.kind() as u8);
+    assert_eq!(M::decode(read.kind, &read.payload).as_ref(), Ok(message));
+}
+
+proptest! {
+    #[test]
+    fn outgoing_round_trips(message in outgoing()) {
+        round_trip(&message);
+    }
+
+    #[test]
+    fn incoming_round_trips(message in incoming()) {
+        round_trip(&message);
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected(message in incoming(), cut in 1usize..64) {
+        let frame = message.to_frame().unwrap();
+        prop_assume!(frame.len() > 9, "empty payloads cannot be truncated");
+        let cut = cut.min(frame.len() - 9);
+        let mut payload = frame[9..].to_vec();
+        payload.truncate(payload.len() - cut);
+        prop_assert!(IncomingMessage::decode(frame[8], &payload).is_err());
+    }
+
+    #[test]
+    fn logs_round_trip(
+        records in prop::collection::vec((any::<u64>(), 0.0f64..1e6, incoming()), 0..8),
+    ) {
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|(tick, time, message)| LogRecord { tick, time, message })
+            .collect();
+        let log: Vec<u8> = records.iter().flat_map(|record| record.to_bytes().unwrap()).collect();
+        prop_assert_eq!(LogRecord::read_all(&log), Ok(records));
+    }
+}
+
+#[test]
+fn sim_connections_are_distinct() {
+    for (i, kind) in SIM_CONNECTION_KINDS.iter().enumerate() {
+        assert!(!SIM_CONNECTION_KINDS[..i].contains(kind), "{kind:?} twice");
+        let decoded = OutgoingMessage::decode_payload(*kind, &[]);
+        assert!(
+            !matches!(decoded, Err(DecodeError::WrongDirection(_))),
+            "{kind:?} is not sent by the sim"
+        );
+    }
+    for camera in shm::CAMERAS {
+        assert!(SIM_CONNECTION_KINDS.contains(&camera));
+        assert!(shm::ring_path("/tmp/ring".as_ref(), camera).is_some());
+    }
+}
+
+#[test]
+fn motors_hold_255_thrusts() {
+    let thrusts = (0..u8::MAX).map(|id| (id, 0.5)).collect();
+    round_trip(&IncomingMessage::Motors(thrusts));
+}
+
+#[test]
+fn motors_reject_more_thrusts() {
+    let thrusts = (0..=u8::MAX).map(|id| (id, 0.5)).collect();
+    assert_eq!(
+        IncomingMessage::Motors(thrusts).to_frame(),
+        Err(EncodeError::TooMany {
+            field: "thrusts",
+            count: 256,
+        })
+    );
+}
+
+#[test]
+fn hello_and_ml_targets_reject_more_entries() {
+    let too_many = |field| Err(EncodeError::TooMany { field, count: 256 });
+    let hello = Hello {
+        thruster_ids: vec![0; 256],
+        ..Default::default()
+    };
+    assert_eq!(
+        IncomingMessage::Hello(hello.clone()).to_frame(),
+        too_many("thrusters")
+    );
+    assert_eq!(
+        OutgoingMessage::Hello(hello).to_frame(),
+        too_many("thrusters")
+    );
+    let hello = Hello {
+        ml_target_kinds: vec![MLTargetKind::None; 256],
+        ..Default::default()
+    };
+    assert_eq!(
+        OutgoingMessage::Hello(hello).to_frame(),
+        too_many("ML target kinds")
+    );
+
+    let target = MLTargetData {
+        kind: MLTargetKind::GateRed,
+        left: 0.0,
+        top: 0.0,
+        right: 1.0,
+        bottom: 1.0,
+    };
+    let targets = std::iter::repeat_n(target, 256).collect();
+    assert_eq!(
+        OutgoingMessage::MlTarget(Stamp::default(), targets, Vec2::ONE).to_frame(),
+        too_many("ML targets")
+    );
+}
+
+#[test]
+fn wrong_direction_is_rejected() {
+    let frame = IncomingMessage::ZedOn(true).to_frame().unwrap();
+    assert_eq!(
+        OutgoingMessage::from_frame(&frame),
+        Err(DecodeError::WrongDirection(MessageKind::ZedOn))
+    );
+    assert_eq!(
+        IncomingMessage::decode(42, &[]),
+        Err(DecodeError::UnknownKind(42))
+    );
+}
+
+/// A small gradient with varying alpha, so lossless encodings are actually checked
+fn test_image() -> ImageMessage {
+    let (width, height) = (16, 8);
+    let rgba = (0..width * height)
+        .flat_map(|i| [(i * 7) as u8, (i * 13) as u8, (i * 29) as u8, (i * 3) as u8])
+        .collect();
+    ImageMessage::raw(
+        Stamp {
+            time: 1.5,
+            tick: 90,
+        },
+        width,
+        height,
+        rgba,
+    )
+}
+
+#[test]
+fn lossless_compression_round_trips() {
+    for encoding in [ImageEncoding::Qoi, ImageEncoding::Png] {
+        let raw = test_image();
+        let mut compressed = raw.clone();
+        compressed.compress(encoding).unwrap();
+        assert_eq!(compressed.encoding, encoding);
+        assert_eq!(compressed.to_rgba8().unwrap(), raw.data, "{encoding:?}");
+
+        let frame = OutgoingMessage::ZedImage(compressed.clone())
+            .to_frame()
+            .unwrap();
+        let decoded = OutgoingMessage::from_frame(&frame);
+        assert_eq!(decoded, Ok(OutgoingMessage::ZedImage(compressed)));
+    }
+}
+
+#[test]
+fn jpeg_keeps_dimensions() {
+    let raw = test_image();
+    let mut compressed = raw.clone();
+    compressed.compress(ImageEncoding::Jpeg).unwrap();
+    let rgba = compressed.to_rgba8().unwrap();
+    assert_eq!(rgba.len(), raw.data.len());
+    // JPEG has no alpha channel
+    assert!(rgba.chunks_exact(4).all(|pixel| pixel[3] == u8::MAX));
+}
+
+#[test]
+fn shm_ring_keeps_the_last_frames() {
+    let path = std::env::temp_dir().join(format!("subsim-ring-test-{}", std::process::id()));
+    let mut writer = ShmRingWriter::create(&path, 2, 100).unwrap();
+    let reader = ShmRingReader::open(&path).unwrap();
+    let image = test_image();
+    let notify = |(slot, sequence): (u32, u64), len: usize| ShmImage {
+        camera: MessageKind::ZedImage,
+        stamp: image.stamp,
+        width: image.width,
+        height: image.height,
+        encoding: image.encoding,
+        slot,
+        sequence,
+        len: len as u64,
+    };
+
+    let frames: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 64 + i as usize]).collect();
+    let notifications: Vec<_> = frames
+        .iter()
+        .map(|frame| notify(writer.write(frame).unwrap(), frame.len()))
+        .collect();
+    // The first frame's slot has been reused by the third
+    assert_eq!(reader.read(&notifications[0]), None);
+    assert_eq!(reader.read(&notifications[1]).as_ref(), Some(&frames[1]));
+    assert_eq!(reader.read(&notifications[2]).as_ref(), Some(&frames[2]));
+    assert!(writer.write(&[0; 200]).is_err());
+    std::fs::remove_file(path).unwrap();
+}